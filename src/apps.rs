@@ -1,94 +1,642 @@
-use crate::config::{AppCFG, PrefCFG};
-use freedesktop_entry_parser::{parse_entry, Entry};
+use crate::cache::{store_icon_paths, IndexCache};
+use crate::config::{AppCFG, PrefCFG, SearchWeightsCFG};
+use crate::ipc::{Incoming, Request, Response};
+use crate::watcher::watch_applications;
+use freedesktop_entry_parser::{parse_entry, AttrSelector, Entry};
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use linicon::lookup_icon;
 use linicon_theme::get_icon_theme;
 use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
-use single_instance::SingleInstance;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
-/// The paths where the desktop files and binaries are located. Will be exported to a config file
-/// and inserted in the defaults
-const APPLICATION_PATHS: [&str; 6] = [
-    "/usr/share/applications",
+/// Data directory of the user, used if `XDG_DATA_HOME` is not set
+const DATA_HOME: &str = "$HOME/.local/share";
+/// The paths where the desktop files and binaries are located besides the one in the data
+/// directory of the user, in order of precedence. Used if `XDG_DATA_DIRS` is not set
+const APPLICATION_PATHS: [&str; 5] = [
+    "$HOME/.local/state/home-manager/gcroots/current-home/home-path/share/applications",
     "/usr/local/share/applications",
-    "$HOME/.local/share/applications",
+    "/usr/share/applications",
     "/var/lib/flatpak/exports/share/applications",
     "/run/current-system/sw/share/applications",
-    "$HOME/.local/state/home-manager/gcroots/current-home/home-path/share/applications",
 ];
-/// The type of application. Either a binary (not yet supported) or a Desktop file
+/// Terminal emulators tried in order if neither the config nor `$TERMINAL` define one, together
+/// with the arguments needed to make them execute a program
+const TERMINAL_EMULATORS: [(&str, &[&str]); 10] = [
+    ("x-terminal-emulator", &["-e"]),
+    ("alacritty", &["-e"]),
+    ("kitty", &[]),
+    ("foot", &[]),
+    ("wezterm", &["start", "--"]),
+    ("gnome-terminal", &["--"]),
+    ("konsole", &["-e"]),
+    ("xfce4-terminal", &["-x"]),
+    ("urxvt", &["-e"]),
+    ("xterm", &["-e"]),
+];
+/// The type of application. Either a Desktop file, an executable in `$PATH` or an entry piped in
+/// through stdin
 #[derive(Clone, Eq, PartialEq, Default, Serialize, Deserialize, Hash, Debug)]
 enum ApplicationType {
     #[default]
     DesktopFile,
-    Stdout, // BINARY,
+    Binary,
+    Stdout,
+    /// A command typed into the search box, which is run through `$SHELL -c`
+    Shell,
 }
 const LOCAL_DIR: &str = "$HOME/.local/share/aphorme/preferred_apps.json";
+const COMMAND_HISTORY_DIR: &str = "$HOME/.local/share/aphorme/command_history.json";
+/// Number of shell commands kept in the history
+const MAX_COMMAND_HISTORY: usize = 100;
+/// Number of launch timestamps remembered per application
+const MAX_LAUNCHES: usize = 100;
+/// Prefixes of the preference keys, which namespace them by application type. Keys without one
+/// are application names as used up to 0.1.20
+const PREFERENCE_KEY_PREFIXES: [&str; 4] = ["desktop:", "binary:", "dmenu:", "shell:"];
+/// Number of selections remembered per query and application
+const MAX_QUERY_SELECTIONS: usize = 20;
+/// Selections are remembered for every prefix of the query up to this length
+const MAX_QUERY_LENGTH: usize = 10;
+/// Timestamps older than this many half-lives barely count and are dropped on save
+const MAX_AGE_HALF_LIVES: f64 = 10.0;
+/// Half-life in days of a launch, if not configured
+const DEFAULT_HALF_LIFE: f64 = 14.0;
+/// Prefix marking the search string as a shell command, if not configured
+const DEFAULT_SHELL_PREFIX: &str = ">";
+/// Weight of a single recent selection for the same query, if not configured
+const DEFAULT_QUERY_WEIGHT: i64 = 30;
+/// Content of the preference file
 #[derive(Default, Serialize, Deserialize)]
+struct PreferenceData {
+    /// Unix timestamps of the launches of each application, oldest first
+    launches: HashMap<String, Vec<u64>>,
+    /// Unix timestamps of the selections of each application per query prefix, oldest first
+    #[serde(default)]
+    queries: HashMap<String, HashMap<String, Vec<u64>>>,
+    /// Entry keys of the pinned applications, in the order they were pinned
+    #[serde(default)]
+    pinned: Vec<String>,
+    /// Entry keys of the hidden applications
+    #[serde(default)]
+    hidden: Vec<String>,
+}
+/// Formats of the preference file, including the ones of previous versions
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPreferences {
+    Current(PreferenceData),
+    /// Launch counts, used up to 0.1.20
+    Counts(HashMap<String, i64>),
+}
+impl PreferenceData {
+    /// Reads the preference file, migrating previous formats. A missing file yields empty
+    /// preferences, an unreadable or corrupt one an error
+    fn read(path: &Path) -> Result<Self, String> {
+        let content: String = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(format!("{err:?}")),
+        };
+        match serde_json::from_str(&content) {
+            Ok(StoredPreferences::Current(data)) => Ok(data),
+            Ok(StoredPreferences::Counts(counts)) => Ok(Self::from_counts(path, counts)),
+            Err(err) => Err(format!("{err}")),
+        }
+    }
+    /// Converts launch counts into timestamps. As the actual launch times are unknown, the time
+    /// the file was last written is used for all of them
+    fn from_counts(path: &Path, counts: HashMap<String, i64>) -> Self {
+        let last_modified: u64 = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or_else(unix_now);
+        debug!("Migrating launch counts of {} applications", counts.len());
+        PreferenceData {
+            launches: counts
+                .into_iter()
+                .map(|(key, count)| {
+                    let count: usize = (count.max(0) as usize).min(MAX_LAUNCHES);
+                    (key, vec![last_modified; count])
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+    fn apply(&mut self, change: &PreferenceChange) {
+        match change {
+            PreferenceChange::Launch {
+                key,
+                query,
+                timestamp,
+            } => {
+                push_timestamp(
+                    self.launches.entry(key.clone()).or_default(),
+                    *timestamp,
+                    MAX_LAUNCHES,
+                );
+                for prefix in query_prefixes(query) {
+                    push_timestamp(
+                        self.queries
+                            .entry(prefix)
+                            .or_default()
+                            .entry(key.clone())
+                            .or_default(),
+                        *timestamp,
+                        MAX_QUERY_SELECTIONS,
+                    );
+                }
+            }
+            PreferenceChange::Pin { key, pinned } => set_listed(&mut self.pinned, key, *pinned),
+            PreferenceChange::Hide { key, hidden } => set_listed(&mut self.hidden, key, *hidden),
+        }
+    }
+}
+/// Adds `key` to the end of `list` or removes it
+fn set_listed(list: &mut Vec<String>, key: &str, listed: bool) {
+    list.retain(|k| k != key);
+    if listed {
+        list.push(key.to_owned());
+    }
+}
+/// A change which has not been written to the preference file yet
+enum PreferenceChange {
+    Launch {
+        key: String,
+        query: String,
+        timestamp: u64,
+    },
+    Pin {
+        key: String,
+        pinned: bool,
+    },
+    Hide {
+        key: String,
+        hidden: bool,
+    },
+}
+/// Remembers when applications were launched and ranks them by frecency, i.e. launches weighted
+/// by an exponential decay over their age
+#[derive(Default)]
 struct PreferredApps {
     path: PathBuf,
-    weight_map: HashMap<String, i64>,
+    data: PreferenceData,
+    /// Changes since the last save. Replayed on top of the file content when saving, so
+    /// changes from other instances are not lost
+    pending: Vec<PreferenceChange>,
+    /// Preference keys by application name, for migrating the preferences of previous versions
+    key_by_name: HashMap<String, String>,
     max_weight: i64,
+    query_weight: i64,
+    /// Half-life of a launch in seconds
+    half_life: f64,
 }
 impl PreferredApps {
     pub fn new(home_dir: &str, preference_cfg: &PrefCFG) -> Self {
         let mut preferred_map: PreferredApps = PreferredApps {
             path: PathBuf::from(LOCAL_DIR.replace("$HOME", home_dir)),
-            weight_map: HashMap::new(),
+            data: PreferenceData::default(),
+            pending: Vec::new(),
+            key_by_name: HashMap::new(),
             max_weight: preference_cfg.max_weight,
+            query_weight: preference_cfg.query_weight.unwrap_or(DEFAULT_QUERY_WEIGHT),
+            // Guards against division by zero
+            half_life: (preference_cfg.half_life_days.unwrap_or(DEFAULT_HALF_LIFE) * 24.0 * 3600.0)
+                .max(1.0),
+        };
+        preferred_map.data = match PreferenceData::read(&preferred_map.path) {
+            Ok(data) => data,
+            Err(err) => {
+                let backup: PathBuf = backup_path(&preferred_map.path);
+                error!(
+                    "Preferred apps in {:?} could not be read ({}), falling back to {:?}",
+                    preferred_map.path, err, backup
+                );
+                PreferenceData::read(&backup).unwrap_or_else(|err| {
+                    error!(
+                        "Backup {:?} could not be read either ({}), starting without preferred apps",
+                        backup, err
+                    );
+                    PreferenceData::default()
+                })
+            }
         };
-        if Path::new(&preferred_map.path).exists() {
-            if let Ok(preference_file_content) = fs::read_to_string(&preferred_map.path) {
-                preferred_map.weight_map = serde_json::from_str(&preference_file_content)
-                    .ok()
-                    .unwrap_or_default();
-            };
-        }
         preferred_map
     }
-    pub fn save(&self) {
-        // error!("{:#?}", self.weight_map);
-        if let Some(containing_folder) = self.path.parent() {
-            if !Path::new(&containing_folder).exists() {
-                if let Err(err) = std::fs::create_dir_all(containing_folder) {
-                    error!("{:?}", err);
-                    return;
+    /// Merges the pending launches into the current content of the preference file and replaces
+    /// it atomically. The previous content is kept as backup
+    pub fn save(&mut self) {
+        let Some(_lock) = lock_file(&self.path) else {
+            return;
+        };
+        match PreferenceData::read(&self.path) {
+            Ok(data) => {
+                self.data = data;
+                migrate_preferences(&mut self.data, &self.key_by_name);
+                for change in &self.pending {
+                    self.data.apply(change);
                 }
-            }
-            if let Ok(mut fileptr) = File::create(&self.path) {
-                // error!("{:#?}", serde_json::to_string(&self.weight_map));
-                if let Err(err) = fileptr.write_all(
-                    serde_json::to_string(&self.weight_map)
-                        .unwrap_or_default()
-                        .as_bytes(),
-                ) {
-                    error!("{:?}", err);
+                if self.path.exists() {
+                    if let Err(err) = fs::copy(&self.path, backup_path(&self.path)) {
+                        error!("Could not back up {:?}, due to {:?}", self.path, err);
+                    }
                 }
             }
+            // The backup is kept, as it is the last known good state
+            Err(err) => error!(
+                "Preferred apps in {:?} could not be read ({}), overwriting them",
+                self.path, err
+            ),
+        }
+        self.prune();
+        if save_json(
+            &self.path,
+            &serde_json::to_string(&self.data).unwrap_or_default(),
+        ) {
+            self.pending.clear();
         }
     }
-    pub fn update_preferrence(&mut self, application: &Application) {
-        match self.weight_map.get_mut(&application.name) {
-            Some(weight) => {
-                if *weight <= self.max_weight {
-                    *weight += 1;
-                }
-            }
-            None => {
-                self.weight_map.insert(application.name.clone(), 1);
+    /// Drops timestamps, which are too old to make a difference
+    fn prune(&mut self) {
+        let oldest: u64 = unix_now().saturating_sub((self.half_life * MAX_AGE_HALF_LIVES) as u64);
+        let prune_map = |map: &mut HashMap<String, Vec<u64>>| {
+            map.values_mut()
+                .for_each(|timestamps| timestamps.retain(|t| *t >= oldest));
+            map.retain(|_, timestamps| !timestamps.is_empty());
+        };
+        prune_map(&mut self.data.launches);
+        self.data.queries.values_mut().for_each(prune_map);
+        self.data
+            .queries
+            .retain(|_, selections| !selections.is_empty());
+    }
+    /// Remembers the launch of the application and that it was chosen for the query
+    pub fn update_preferrence(&mut self, application: &Application, query: &str) {
+        self.change(PreferenceChange::Launch {
+            key: application.preference_key(),
+            query: query.to_owned(),
+            timestamp: unix_now(),
+        });
+    }
+    pub fn set_pinned(&mut self, application: &Application, pinned: bool) {
+        self.change(PreferenceChange::Pin {
+            key: application.entry_key(),
+            pinned,
+        });
+    }
+    pub fn set_hidden(&mut self, application: &Application, hidden: bool) {
+        self.change(PreferenceChange::Hide {
+            key: application.entry_key(),
+            hidden,
+        });
+    }
+    fn change(&mut self, change: PreferenceChange) {
+        self.data.apply(&change);
+        self.pending.push(change);
+    }
+    pub fn has_pinned(&self) -> bool {
+        !self.data.pinned.is_empty()
+    }
+    /// Position of the application in the pinned list, if it is pinned
+    pub fn pin_position(&self, application: &Application) -> Option<usize> {
+        let key: String = application.entry_key();
+        self.data.pinned.iter().position(|pinned| *pinned == key)
+    }
+    pub fn is_hidden(&self, application: &Application) -> bool {
+        let key: String = application.entry_key();
+        self.data.hidden.contains(&key)
+    }
+    /// A single recent launch is worth `max_weight`, a single recent selection for the same query
+    /// `query_weight`. Further launches add logarithmically, so frequently used applications don't
+    /// drown out better matches
+    pub fn get_weight(&self, application: &Application, query: &str) -> i64 {
+        let key: String = application.preference_key();
+        let launch_weight: f64 =
+            self.max_weight as f64 * (1.0 + self.frecency(self.data.launches.get(&key))).log2();
+        let query_weight: f64 = query_prefixes(query)
+            .last()
+            .and_then(|query| self.data.queries.get(query))
+            .map(|selections| {
+                self.query_weight as f64 * (1.0 + self.frecency(selections.get(&key))).log2()
+            })
+            .unwrap_or_default();
+        (launch_weight + query_weight).round() as i64
+    }
+    /// Re-keys preferences stored by application name (up to 0.1.20) to the keys of the
+    /// `applications` with that name. Preferences of unknown names are kept until they expire
+    pub fn migrate_names(&mut self, applications: &[Application]) {
+        let mut key_by_name: HashMap<String, String> = HashMap::new();
+        for application in applications {
+            for name in [
+                Some(&application.name),
+                application.untranslated_name.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                key_by_name
+                    .entry(name.clone())
+                    .or_insert_with(|| application.preference_key());
             }
+        }
+        self.key_by_name = key_by_name;
+        if migrate_preferences(&mut self.data, &self.key_by_name) {
+            debug!("Migrated name based preferences");
+            self.save();
+        }
+    }
+    /// Sum of the timestamps, each weighted by an exponential decay over its age
+    fn frecency(&self, timestamps: Option<&Vec<u64>>) -> f64 {
+        let now: u64 = unix_now();
+        timestamps
+            .into_iter()
+            .flatten()
+            .map(|timestamp| 0.5_f64.powf(now.saturating_sub(*timestamp) as f64 / self.half_life))
+            .sum()
+    }
+}
+/// Migrates the legacy name keys of launches and query selections. Returns whether any key was
+/// migrated
+fn migrate_preferences(data: &mut PreferenceData, key_by_name: &HashMap<String, String>) -> bool {
+    let mut migrated: bool = migrate_keys(&mut data.launches, key_by_name, MAX_LAUNCHES);
+    for selections in data.queries.values_mut() {
+        migrated |= migrate_keys(selections, key_by_name, MAX_QUERY_SELECTIONS);
+    }
+    migrated
+}
+/// Moves the timestamps of legacy name keys to the keys in `key_by_name`. Returns whether any key
+/// was migrated
+fn migrate_keys(
+    map: &mut HashMap<String, Vec<u64>>,
+    key_by_name: &HashMap<String, String>,
+    max: usize,
+) -> bool {
+    let legacy_keys: Vec<String> = map
+        .keys()
+        .filter(|key| {
+            !PREFERENCE_KEY_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+                && key_by_name.contains_key(key.as_str())
+        })
+        .cloned()
+        .collect();
+    for legacy_key in &legacy_keys {
+        let Some(mut timestamps) = map.remove(legacy_key) else {
+            continue;
+        };
+        let migrated: &mut Vec<u64> = map
+            .entry(key_by_name[legacy_key.as_str()].clone())
+            .or_default();
+        migrated.append(&mut timestamps);
+        migrated.sort_unstable();
+        if migrated.len() > max {
+            migrated.drain(..migrated.len() - max);
+        }
+    }
+    !legacy_keys.is_empty()
+}
+/// Appends the timestamp and drops the oldest ones beyond `max`
+fn push_timestamp(timestamps: &mut Vec<u64>, timestamp: u64, max: usize) {
+    timestamps.push(timestamp);
+    if timestamps.len() > max {
+        timestamps.drain(..timestamps.len() - max);
+    }
+}
+/// Normalized prefixes of the query, shortest first, up to `MAX_QUERY_LENGTH` characters
+fn query_prefixes(query: &str) -> Vec<String> {
+    let query: String = query.trim().to_lowercase();
+    query
+        .char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .take(MAX_QUERY_LENGTH)
+        .map(|end| query[..end].to_owned())
+        .collect()
+}
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+/// Shell commands run from the search box, most recent first
+#[derive(Default, Serialize, Deserialize)]
+struct CommandHistory {
+    path: PathBuf,
+    commands: Vec<String>,
+    /// Commands run since the history was last saved, oldest first
+    pending: Vec<String>,
+}
+impl CommandHistory {
+    pub fn new(home_dir: &str) -> Self {
+        let path: PathBuf = PathBuf::from(COMMAND_HISTORY_DIR.replace("$HOME", home_dir));
+        let commands: Vec<String> = Self::read(&path);
+        CommandHistory {
+            path,
+            commands,
+            pending: Vec::new(),
+        }
+    }
+    fn read(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+    /// Adds the pending commands to the current content of the history file, so commands run by
+    /// other instances in the meantime are kept
+    pub fn save(&mut self) {
+        let Some(_lock) = lock_file(&self.path) else {
+            return;
+        };
+        self.commands = Self::read(&self.path);
+        for command in std::mem::take(&mut self.pending) {
+            self.push(command);
+        }
+        save_json(
+            &self.path,
+            &serde_json::to_string(&self.commands).unwrap_or_default(),
+        );
+    }
+    pub fn add(&mut self, command: &str) {
+        self.push(command.to_owned());
+        self.pending.push(command.to_owned());
+    }
+    fn push(&mut self, command: String) {
+        self.commands.retain(|c| *c != command);
+        self.commands.insert(0, command);
+        self.commands.truncate(MAX_COMMAND_HISTORY);
+    }
+}
+/// Writes `json` to `path`, creating the containing folder if necessary. The content is written
+/// to a temporary file first, which then replaces `path` atomically, so a crash never leaves a
+/// partially written file behind. Returns whether writing succeeded
+pub(crate) fn save_json(path: &Path, json: &str) -> bool {
+    let Some(containing_folder) = path.parent() else {
+        return false;
+    };
+    if let Err(err) = fs::create_dir_all(containing_folder) {
+        error!("{:?}", err);
+        return false;
+    }
+    let temp_path: PathBuf = path_with_suffix(path, ".tmp");
+    let result = File::create(&temp_path)
+        .and_then(|mut fileptr| {
+            fileptr.write_all(json.as_bytes())?;
+            fileptr.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(err) = result {
+        error!("Could not write {:?}, due to {:?}", path, err);
+        let _ = fs::remove_file(&temp_path);
+        return false;
+    }
+    true
+}
+/// Takes an exclusive lock on a lock file next to `path`, blocking until other instances have
+/// released it. The lock is released when the returned file is dropped
+fn lock_file(path: &Path) -> Option<File> {
+    // Without a home directory there is nothing to save
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    let lock_path: PathBuf = path_with_suffix(path, ".lock");
+    if let Some(containing_folder) = lock_path.parent() {
+        if let Err(err) = fs::create_dir_all(containing_folder) {
+            error!("{:?}", err);
+            return None;
+        }
+    }
+    let lock = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .and_then(|lock| lock.lock().map(|_| lock));
+    match lock {
+        Ok(lock) => Some(lock),
+        Err(err) => {
+            error!("Could not lock {:?}, due to {:?}", lock_path, err);
+            None
+        }
+    }
+}
+fn backup_path(path: &Path) -> PathBuf {
+    path_with_suffix(path, ".bak")
+}
+/// Appends `suffix` to the file name of `path`, e.g. `preferred_apps.json.bak`
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(suffix);
+    PathBuf::from(file_name)
+}
+/// Options of a single invocation, mostly mirroring the flags of dmenu
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MenuOptions {
+    /// Shown in front of the search field
+    pub prompt: Option<String>,
+    /// Match regardless of case. Otherwise the case only matters if the search string contains
+    /// upper case letters
+    pub case_insensitive: bool,
+    /// Number of rows shown
+    pub lines: Option<usize>,
+    /// Shown in the empty search field
+    pub placeholder: Option<String>,
+    /// Entry selected initially
+    pub select: Option<String>,
+    /// Select the remaining entry right away, once only one matches
+    pub auto_select: bool,
+    /// What is printed for the selected entry. `s` is replaced by its text, `i` by its index
+    /// (starting at 0), `d` by its index starting at 1 and `f` by the search string
+    pub format: Option<String>,
+    /// Accept the search string, if nothing matches or with Ctrl+Enter
+    pub allow_custom: bool,
+    /// Separates the fields of the piped in entries. Runs of whitespace, if not given
+    pub delimiter: Option<String>,
+    /// Fields shown and searched
+    pub with_nth: Option<Fields>,
+    /// Fields printed for the selected entry
+    pub accept_nth: Option<Fields>,
+}
+/// Field index expression like the one of fzf, e.g. `1,3..`. Fields are counted from 1, negative
+/// indices count backwards from the last field
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fields(Vec<(Option<i64>, Option<i64>)>);
+impl FromStr for Fields {
+    type Err = String;
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let index = |index: &str| match index.parse::<i64>() {
+            Ok(0) | Err(_) => Err(format!("invalid field index {index:?}")),
+            Ok(index) => Ok(index),
         };
+        // Open ends of ranges are left out, e.g. `..2`
+        let bound = |bound: &str| match bound.is_empty() {
+            true => Ok(None),
+            false => index(bound).map(Some),
+        };
+        expression
+            .split(',')
+            .map(|range| match range.split_once("..") {
+                Some((start, end)) => Ok((bound(start)?, bound(end)?)),
+                None => index(range).map(|index| (Some(index), Some(index))),
+            })
+            .collect::<Result<Vec<(Option<i64>, Option<i64>)>, String>>()
+            .map(Fields)
     }
-    pub fn get_weight(&self, application: &Application) -> i64 {
-        *self.weight_map.get(&application.name).unwrap_or(&0)
+}
+impl Fields {
+    /// The selected fields in the order of the expression. Fields out of range are left out
+    fn select<'a>(&self, fields: &[&'a str]) -> Vec<&'a str> {
+        let last: i64 = fields.len() as i64 - 1;
+        let resolve = |index: i64| match index > 0 {
+            true => index - 1,
+            false => last + 1 + index,
+        };
+        let mut selected: Vec<&str> = Vec::new();
+        for (start, end) in &self.0 {
+            let start: i64 = start.map_or(0, resolve).max(0);
+            let end: i64 = end.map_or(last, resolve).min(last);
+            for field in start..=end {
+                selected.push(fields[field as usize]);
+            }
+        }
+        selected
+    }
+}
+/// Exit code if the window was closed without selection
+pub const EXIT_CANCELLED: u8 = 1;
+/// Exit code if the running instance could not be reached or another instance could not be
+/// started
+pub const EXIT_ERROR: u8 = 2;
+/// Exit code of the first alternate accept key (Alt+1). The following keys count upwards
+pub const EXIT_CUSTOM_KEY: u8 = 10;
+/// Outcome of an invocation, which prints the selection to stdout
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Selection {
+    /// Printed to stdout, one per selected entry. Empty if nothing was selected
+    pub outputs: Vec<String>,
+    pub exit_code: u8,
+}
+impl Selection {
+    pub fn cancelled() -> Self {
+        Selection {
+            outputs: Vec::new(),
+            exit_code: EXIT_CANCELLED,
+        }
     }
 }
 #[derive(Default)]
@@ -97,29 +645,53 @@ pub struct ApplicationManager {
     pub matches: Vec<(Application, i64)>,
     icon_theme: String,
     loaded_icons: usize,
-    instance: Option<SingleInstance>,
     preferred_applications: PreferredApps,
+    /// Command prefix used to launch applications which need a terminal
+    terminal: Vec<String>,
+    search_weights: SearchWeightsCFG,
+    /// Prefix marking the search string as a shell command
+    shell_prefix: Option<String>,
+    /// Whether shell commands are run in the terminal
+    shell_in_terminal: bool,
+    /// Whether the selected piped in entries are remembered for ranking
+    remember_piped_entries: bool,
+    command_history: CommandHistory,
+    /// Whether the applications were piped in and the selection is printed to stdout
+    stdout_mode: bool,
+    /// The search string of the current matches
+    query: String,
+    /// Applications and number of loaded icons, while entries of another invocation are shown
+    /// instead
+    stashed: Option<(Vec<Application>, usize)>,
+    /// Invocation waiting for the selection of the entries it sent
+    client: Option<Incoming>,
+    /// Applications loaded again in the background, after the application paths changed
+    reload: Option<Receiver<Vec<Application>>>,
+    /// Entries piped in, as they arrive. None once all arrived
+    entries: Option<Receiver<String>>,
+    options: MenuOptions,
+    /// Selection made in stdout mode, until it is taken
+    selection: Option<Selection>,
+    /// Indices of the piped in entries marked for selection
+    marked: BTreeSet<usize>,
+    /// Whether hidden applications are listed as well, so they can be unhidden
+    show_hidden: bool,
 }
 impl ApplicationManager {
     pub fn new(
         config: AppCFG,
         icon: bool,
-        instance: SingleInstance,
-        custom_fields: Vec<String>,
+        custom_inputs: Option<Receiver<String>>,
     ) -> ApplicationManager {
         let mut paths: Vec<String> = config.paths.clone();
         if config.use_default_paths.is_none() || config.use_default_paths == Some(true) {
-            for path in Vec::from(APPLICATION_PATHS)
-                .into_iter()
-                .map(|p| p.to_owned())
-            {
-                paths.push(path);
-            }
+            paths.extend(default_application_paths());
         }
 
         let home_dir_opt = env::var_os("HOME");
 
         let mut preferred_apps: Option<PreferredApps> = None;
+        let mut command_history: Option<CommandHistory> = None;
         match home_dir_opt {
             Some(home_dir) => {
                 paths = paths
@@ -130,85 +702,585 @@ impl ApplicationManager {
                     &home_dir.to_string_lossy(),
                     &config.preferred_apps,
                 ));
+                command_history = Some(CommandHistory::new(&home_dir.to_string_lossy()));
             }
             None => warn!("Impossible to get your home dir!"),
         };
-        let mut applications: Vec<Application>;
-        if custom_fields.is_empty() {
-            applications = collect_applications(&paths);
-            applications.sort();
-        } else {
-            applications = Vec::new();
-            for field in custom_fields {
-                // debug!("{}", field);
-                applications.push(Application {
-                    name: field.clone(),
-                    command: field,
-                    icon_path: None,
-                    icon_name: None,
-                    application_type: ApplicationType::Stdout,
-                });
+        let icon_theme: String = match icon {
+            true => get_icon_theme().unwrap_or_else(|| {
+                warn!("No icon theme found");
+                "".to_string()
+            }),
+            false => String::new(),
+        };
+        let stdout_mode: bool = custom_inputs.is_some();
+        let desktop_actions: bool =
+            config.desktop_actions.is_none() || config.desktop_actions == Some(true);
+        let binaries: bool = config.binaries == Some(true);
+        let mut applications: Vec<Application> = Vec::new();
+        let mut reload: Option<Receiver<Vec<Application>>> = None;
+        if !stdout_mode {
+            applications = load_applications(&paths, desktop_actions, binaries, &icon_theme);
+            reload = watch_applications(paths.iter().map(PathBuf::from).collect()).map(|changes| {
+                reload_on_change(
+                    changes,
+                    paths,
+                    desktop_actions,
+                    binaries,
+                    icon_theme.clone(),
+                )
+            });
+            if let Some(preferred_apps) = preferred_apps.as_mut() {
+                preferred_apps.migrate_names(&applications);
             }
         }
 
-        ApplicationManager {
-            applications: applications.clone(),
-            matches: applications.into_iter().map(|app| (app, 0)).collect(),
-            icon_theme: match icon {
-                true => get_icon_theme().unwrap_or_else(|| {
-                    warn!("No icon theme found");
-                    "".to_string()
-                }),
-                false => String::new(),
-            },
+        let mut application_manager: ApplicationManager = ApplicationManager {
+            applications,
+            matches: Vec::new(),
+            icon_theme,
             loaded_icons: 0,
-            instance: Some(instance),
             preferred_applications: preferred_apps.unwrap_or_default(),
+            terminal: resolve_terminal(config.terminal.as_deref()),
+            search_weights: config.search_weights.unwrap_or_default(),
+            // An empty prefix disables it
+            shell_prefix: Some(
+                config
+                    .shell_prefix
+                    .unwrap_or_else(|| DEFAULT_SHELL_PREFIX.to_owned()),
+            )
+            .filter(|prefix| !prefix.is_empty()),
+            shell_in_terminal: config.shell_in_terminal == Some(true),
+            remember_piped_entries: config.preferred_apps.remember_piped_entries == Some(true),
+            command_history: command_history.unwrap_or_default(),
+            stdout_mode,
+            query: String::new(),
+            stashed: None,
+            client: None,
+            reload,
+            entries: custom_inputs,
+            options: MenuOptions::default(),
+            selection: None,
+            marked: BTreeSet::new(),
+            show_hidden: false,
+        };
+        application_manager.matches = application_manager.initial_matches();
+        application_manager
+    }
+    /// Swaps in the applications reloaded in the background, if the application paths changed.
+    /// Icons already looked up are kept. Returns whether the applications were replaced
+    pub fn reload_applications(&mut self) -> bool {
+        let Some(reload) = &self.reload else {
+            return false;
+        };
+        // Only the latest reload is of interest
+        let Some(mut applications) = reload.try_iter().last() else {
+            return false;
+        };
+        // The applications are hidden behind entries of another invocation at the moment
+        let (current, loaded_icons) = match &mut self.stashed {
+            Some((applications, loaded_icons)) => (applications, loaded_icons),
+            None => (&mut self.applications, &mut self.loaded_icons),
+        };
+        let icon_paths: HashMap<(Option<&Path>, &str), &PathBuf> = current
+            .iter()
+            .filter_map(|application| {
+                application.icon_path.as_ref().map(|icon_path| {
+                    (
+                        (application.desktop_file(), application.name.as_str()),
+                        icon_path,
+                    )
+                })
+            })
+            .collect();
+        for application in &mut applications {
+            if application.icon_path.is_none() {
+                application.icon_path = icon_paths
+                    .get(&(application.desktop_file(), application.name.as_str()))
+                    .map(|icon_path| icon_path.to_path_buf());
+            }
+        }
+        debug!(
+            "Reloaded {} applications, previously {}",
+            applications.len(),
+            current.len()
+        );
+        *current = applications;
+        // Only the icons of new applications are looked up, the others are skipped
+        *loaded_icons = 0;
+        if self.stashed.is_none() {
+            self.refresh_matches();
+        }
+        true
+    }
+    /// All applications, which are not hidden, with the pinned ones on top
+    fn initial_matches(&self) -> Vec<(Application, i64)> {
+        let mut matches: Vec<(Application, i64)> = self
+            .applications
+            .iter()
+            .filter(|application| !self.is_excluded(application))
+            .map(|application| (application.clone(), 0))
+            .collect();
+        self.sort_pinned_first(&mut matches);
+        matches
+    }
+    fn sort_pinned_first(&self, matches: &mut [(Application, i64)]) {
+        if !self.preferred_applications.has_pinned() {
+            return;
         }
+        matches.sort_by_cached_key(|m| {
+            self.preferred_applications
+                .pin_position(&m.0)
+                .unwrap_or(usize::MAX)
+        });
+    }
+    /// Appends the new matches, placing the pinned ones among the pinned matches on top. Avoids
+    /// sorting all matches again, as entries may be piped in by the thousands
+    fn insert_pinned_first(&mut self, new_matches: Vec<(Application, i64)>) {
+        if !self.preferred_applications.has_pinned() {
+            self.matches.extend(new_matches);
+            return;
+        }
+        let (mut pinned, unpinned): (Vec<_>, Vec<_>) = new_matches
+            .into_iter()
+            .partition(|m: &(Application, i64)| self.is_pinned(&m.0));
+        if !pinned.is_empty() {
+            let pinned_count: usize = self
+                .matches
+                .iter()
+                .take_while(|m| self.is_pinned(&m.0))
+                .count();
+            pinned.splice(0..0, self.matches.drain(..pinned_count));
+            self.sort_pinned_first(&mut pinned);
+            self.matches.splice(0..0, pinned);
+        }
+        self.matches.extend(unpinned);
     }
     /// Clear the Matches and then from the vector of applications fuzzy find the search_str and  append to the matches
     pub fn find_application(&mut self, search_str: &str) {
-        let matcher = SkimMatcherV2::default();
+        let matcher: SkimMatcherV2 = self.matcher();
         self.matches.clear();
-        for application in &self.applications {
-            let search_match: Option<i64> = matcher.fuzzy_match(&application.name, search_str);
+        self.query = search_str.to_owned();
+        if let Some(command) = self.strip_shell_prefix(search_str) {
+            self.find_shell_command(&matcher, command);
+            return;
+        }
+        self.matches = self.score_applications(&self.applications, &matcher, search_str);
+        self.matches.sort_by_key(|m| Reverse(m.1));
+        if search_str.is_empty() {
+            let mut matches: Vec<(Application, i64)> = std::mem::take(&mut self.matches);
+            self.sort_pinned_first(&mut matches);
+            self.matches = matches;
+        }
+        if self.matches.is_empty() && !self.stdout_mode && !search_str.trim().is_empty() {
+            self.matches
+                .push((self.shell_application(search_str.trim()), 0));
+        }
+    }
+    fn matcher(&self) -> SkimMatcherV2 {
+        match self.options.case_insensitive {
+            true => SkimMatcherV2::default().ignore_case(),
+            false => SkimMatcherV2::default(),
+        }
+    }
+    /// Index of the match to select initially. The first one named like `select` in the
+    /// options, or else the first one containing it
+    pub fn preselection(&self) -> Option<usize> {
+        let select: &str = self.options.select.as_deref()?;
+        self.matches
+            .iter()
+            .position(|m| m.0.name == select)
+            .or_else(|| {
+                let select: String = select.to_lowercase();
+                self.matches
+                    .iter()
+                    .position(|m| m.0.name.to_lowercase().contains(&select))
+            })
+    }
+    /// Whether only one entry matches and it should be selected right away. Waits until all
+    /// piped in entries arrived, as more might match
+    pub fn auto_select(&self) -> bool {
+        self.options.auto_select
+            && self.entries.is_none()
+            && self.matches.len() == 1
+            && self.matches[0].0.application_type != ApplicationType::Shell
+            && !self.matches[0].0.nonselectable
+    }
+    /// The applications matching the search string, which are not hidden, with their scores
+    fn score_applications(
+        &self,
+        applications: &[Application],
+        matcher: &SkimMatcherV2,
+        search_str: &str,
+    ) -> Vec<(Application, i64)> {
+        let mut matches: Vec<(Application, i64)> = Vec::new();
+        for application in applications {
+            if self.is_excluded(application) {
+                continue;
+            }
+            let search_match: Option<i64> =
+                application.match_score(matcher, search_str, &self.search_weights);
             debug!(
                 "{} = {} : {:?}",
                 search_str, &application.name, search_match
             );
             if let Some(score) = search_match {
-                self.matches.push((
+                matches.push((
                     application.clone(),
-                    score + self.preferred_applications.get_weight(application),
+                    score
+                        + self
+                            .preferred_applications
+                            .get_weight(application, search_str),
                 ));
             }
         }
-        self.matches.sort_by(|a, b| b.1.cmp(&a.1));
+        matches
+    }
+    /// Returns the command if the search string starts with the shell prefix
+    fn strip_shell_prefix<'a>(&self, search_str: &'a str) -> Option<&'a str> {
+        if self.stdout_mode {
+            return None;
+        }
+        search_str
+            .strip_prefix(self.shell_prefix.as_deref()?)
+            .map(|command| command.trim())
+    }
+    /// Fills the matches with the typed command followed by the matching commands of the history
+    fn find_shell_command(&mut self, matcher: &SkimMatcherV2, command: &str) {
+        if !command.is_empty() {
+            self.matches.push((self.shell_application(command), 0));
+        }
+        let mut history_matches: Vec<(Application, i64)> = self
+            .command_history
+            .commands
+            .iter()
+            .filter(|history_command| history_command.as_str() != command)
+            .filter_map(|history_command| {
+                matcher
+                    .fuzzy_match(history_command, command)
+                    .map(|score| (self.shell_application(history_command), score))
+            })
+            .collect();
+        // Stable, so equally good matches stay in the order of recency
+        history_matches.sort_by_key(|m| Reverse(m.1));
+        self.matches.extend(history_matches);
+    }
+    fn shell_application(&self, command: &str) -> Application {
+        Application {
+            name: command.to_owned(),
+            command: command.to_owned(),
+            terminal: self.shell_in_terminal,
+            application_type: ApplicationType::Shell,
+            ..Default::default()
+        }
+    }
+    pub fn is_pinned(&self, application: &Application) -> bool {
+        self.preferred_applications
+            .pin_position(application)
+            .is_some()
+    }
+    /// Pins the selected application to the top of the list without search string or unpins it
+    pub fn toggle_pinned(&mut self, selected: usize) {
+        let Some(application) = self.selectable_match(selected) else {
+            return;
+        };
+        let pinned: bool = !self.is_pinned(&application);
+        self.preferred_applications.set_pinned(&application, pinned);
+        self.preferred_applications.save();
+        self.refresh_matches();
+    }
+    pub fn is_hidden(&self, application: &Application) -> bool {
+        self.preferred_applications.is_hidden(application)
+    }
+    /// Whether the application is left out of the matches, as it is hidden
+    fn is_excluded(&self, application: &Application) -> bool {
+        !self.show_hidden && self.preferred_applications.is_hidden(application)
+    }
+    /// Hides the selected application from all searches or unhides it
+    pub fn toggle_hidden(&mut self, selected: usize) {
+        let Some(application) = self.selectable_match(selected) else {
+            return;
+        };
+        let hidden: bool = !self.is_hidden(&application);
+        self.preferred_applications.set_hidden(&application, hidden);
+        self.preferred_applications.save();
+        self.refresh_matches();
+    }
+    /// Lists the hidden applications as well, so they can be unhidden, or leaves them out again
+    pub fn toggle_show_hidden(&mut self) {
+        self.show_hidden = !self.show_hidden;
+        self.refresh_matches();
+    }
+    /// The selected match, if it is an application, which can be pinned or hidden. Piped in
+    /// entries and shell commands can't
+    fn selectable_match(&self, selected: usize) -> Option<Application> {
+        self.matches
+            .get(selected)
+            .map(|m| m.0.clone())
+            .filter(|application| {
+                matches!(
+                    application.application_type,
+                    ApplicationType::DesktopFile | ApplicationType::Binary
+                )
+            })
     }
-    pub fn execute_first_match(&mut self, selected: usize) {
-        self.instance = None;
-        let selected_match: &Application = &self.matches[selected].0;
+    fn refresh_matches(&mut self) {
+        if self.query.is_empty() {
+            self.matches = self.initial_matches();
+        } else {
+            let query: String = self.query.clone();
+            self.find_application(&query);
+        }
+    }
+    /// The search string of the current matches
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+    /// Applies the search string and entries of a request of another invocation. Select
+    /// requests are answered once an entry is selected, all others right away
+    pub fn handle_request(&mut self, mut incoming: Incoming) {
+        let query: Option<String> = match &mut incoming.request {
+            Request::Show { query, options } => {
+                let query: Option<String> = query.take();
+                self.options = std::mem::take(options);
+                incoming.reply(Response::Ok);
+                query
+            }
+            Request::Toggle => {
+                incoming.reply(Response::Ok);
+                None
+            }
+            Request::Select { query, options } => {
+                let query: Option<String> = query.take();
+                self.options = std::mem::take(options);
+                // Without entries, if the connection could not be read
+                let entries: Receiver<String> =
+                    incoming.entries.take().unwrap_or_else(|| mpsc::channel().1);
+                self.select_from(entries, incoming);
+                query
+            }
+        };
+        match query {
+            Some(query) => self.find_application(&query),
+            None => self.refresh_matches(),
+        }
+    }
+    /// Shows the entries sent by another invocation instead of the applications, until the
+    /// manager is reset. The selection is sent back to the `client`
+    fn select_from(&mut self, entries: Receiver<String>, client: Incoming) {
+        if let Some(previous) = self.client.replace(client) {
+            previous.reply(Response::Selection(Selection::cancelled()));
+        }
+        let previous: Vec<Application> = std::mem::take(&mut self.applications);
+        if self.stashed.is_none() && !self.stdout_mode {
+            self.stashed = Some((previous, self.loaded_icons));
+        }
+        self.entries = Some(entries);
+        self.marked.clear();
+        self.loaded_icons = 0;
+        self.stdout_mode = true;
+        self.query.clear();
+        self.matches = self.initial_matches();
+    }
+    pub fn options(&self) -> &MenuOptions {
+        &self.options
+    }
+    pub fn set_options(&mut self, options: MenuOptions) {
+        self.options = options;
+        self.refresh_matches();
+    }
+    /// Appends the entries piped in since the last call and matches them against the search
+    /// string. Returns whether there were new entries
+    pub fn receive_entries(&mut self) -> bool {
+        let Some(entries) = &self.entries else {
+            return false;
+        };
+        let mut received: Vec<String> = Vec::new();
+        loop {
+            match entries.try_recv() {
+                Ok(entry) => received.push(entry),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.entries = None;
+                    break;
+                }
+            }
+        }
+        if received.is_empty() {
+            return false;
+        }
+        let start: usize = self.applications.len();
+        self.applications
+            .extend(stdout_applications(received, start, &self.options));
+        if self.query.is_empty() {
+            let new_matches: Vec<(Application, i64)> = self.applications[start..]
+                .iter()
+                .filter(|application| !self.is_excluded(application))
+                .map(|application| (application.clone(), 0))
+                .collect();
+            self.insert_pinned_first(new_matches);
+        } else {
+            let mut new_matches: Vec<(Application, i64)> =
+                self.score_applications(&self.applications[start..], &self.matcher(), &self.query);
+            new_matches.sort_by_key(|m| Reverse(m.1));
+            let matches: Vec<(Application, i64)> = std::mem::take(&mut self.matches);
+            self.matches = merge_by_score(matches, new_matches);
+        }
+        true
+    }
+    /// Clears the search string and restores the applications, so the manager can be reused for
+    /// the next window. An invocation still waiting for a selection is told that there is none
+    pub fn reset(&mut self) {
+        if let Some(client) = self.client.take() {
+            client.reply(Response::Selection(Selection::cancelled()));
+        }
+        if let Some((applications, loaded_icons)) = self.stashed.take() {
+            self.applications = applications;
+            self.loaded_icons = loaded_icons;
+            self.stdout_mode = false;
+            self.entries = None;
+        }
+        self.options = MenuOptions::default();
+        self.selection = None;
+        self.marked.clear();
+        self.show_hidden = false;
+        self.query.clear();
+        self.matches = self.initial_matches();
+    }
+    /// Launches the selected match or, in stdout mode, records it as selection with the
+    /// `exit_code`. Returns false if the match can't be selected, so the window stays open
+    pub fn execute_first_match(&mut self, selected: usize, exit_code: u8) -> bool {
+        if !self.marked.is_empty() {
+            let marked: Vec<Application> = self
+                .marked
+                .iter()
+                .filter_map(|index| self.applications.get(*index))
+                .cloned()
+                .collect();
+            self.select_entries(&marked, exit_code);
+            return true;
+        }
+        let Some((selected_match, _)) = self.matches.get(selected) else {
+            if !self.accept_custom(exit_code) {
+                debug!("Nothing selected");
+            }
+            return true;
+        };
+        if selected_match.nonselectable {
+            return false;
+        }
 
         match selected_match.application_type {
-            ApplicationType::DesktopFile => {
+            ApplicationType::DesktopFile | ApplicationType::Binary => {
                 self.preferred_applications
-                    .update_preferrence(selected_match);
+                    .update_preferrence(selected_match, &self.query);
                 self.preferred_applications.save();
-                selected_match.run(false);
+                selected_match.run(&self.terminal, false);
+            }
+            ApplicationType::Shell => {
+                self.command_history.add(&selected_match.command);
+                self.command_history.save();
+                selected_match.run(&self.terminal, false);
+            }
+            ApplicationType::Stdout => {
+                let selected_match: Application = selected_match.clone();
+                self.select_entries(&[selected_match], exit_code);
+            }
+        }
+        true
+    }
+    /// Records the piped in entries as selection. They are only remembered if enabled, as they
+    /// may be sensitive (e.g. clipboard contents or names of passwords)
+    fn select_entries(&mut self, entries: &[Application], exit_code: u8) {
+        if self.remember_piped_entries {
+            for entry in entries {
+                self.preferred_applications
+                    .update_preferrence(entry, &self.query);
             }
-            #[allow(clippy::print_stdout)]
-            ApplicationType::Stdout => println!("{}", selected_match.command),
+            self.preferred_applications.save();
+        }
+        let outputs: Vec<String> = entries
+            .iter()
+            .map(|entry| self.format_output(&entry.command, Some(entry.index)))
+            .collect();
+        self.select(Selection { outputs, exit_code });
+    }
+    /// Whether the search string can be accepted as is
+    pub fn accepts_custom(&self) -> bool {
+        self.stdout_mode && self.options.allow_custom
+    }
+    /// Records the search string as selection, if custom input is allowed. Returns whether it
+    /// was accepted
+    pub fn accept_custom(&mut self, exit_code: u8) -> bool {
+        if !self.accepts_custom() {
+            return false;
+        }
+        let output: String = self.format_output(&self.query, None);
+        self.select(Selection {
+            outputs: vec![output],
+            exit_code,
+        });
+        true
+    }
+    /// Marks the selected piped in entry to be selected along with the other marked ones, or
+    /// unmarks it
+    pub fn toggle_mark(&mut self, selected: usize) {
+        let Some((entry, _)) = self.matches.get(selected) else {
+            return;
+        };
+        if entry.application_type != ApplicationType::Stdout || entry.nonselectable {
+            return;
+        }
+        if !self.marked.remove(&entry.index) {
+            self.marked.insert(entry.index);
+        }
+    }
+    /// Whether the entries are piped in and the selection is printed to stdout
+    pub fn stdout_mode(&self) -> bool {
+        self.stdout_mode
+    }
+    /// All applications or piped in entries, whether they match or not
+    pub fn applications(&self) -> &[Application] {
+        &self.applications
+    }
+    pub fn is_marked(&self, application: &Application) -> bool {
+        application.application_type == ApplicationType::Stdout
+            && self.marked.contains(&application.index)
+    }
+    /// Sends the selection to the invocation waiting for it or keeps it to be taken
+    fn select(&mut self, selection: Selection) {
+        match self.client.take() {
+            Some(client) => client.reply(Response::Selection(selection)),
+            None => self.selection = Some(selection),
         }
     }
+    /// The selection made in stdout mode, if any
+    pub fn take_selection(&mut self) -> Option<Selection> {
+        self.selection.take()
+    }
+    /// Replaces the placeholders of the format option. The index of custom input is -1
+    fn format_output(&self, text: &str, index: Option<usize>) -> String {
+        let format: &str = self.options.format.as_deref().unwrap_or("s");
+        format
+            .chars()
+            .map(|placeholder| match placeholder {
+                's' => text.to_owned(),
+                'i' => index.map_or("-1".to_owned(), |index| index.to_string()),
+                'd' => index.map_or("-1".to_owned(), |index| (index + 1).to_string()),
+                'f' => self.query.clone(),
+                other => other.to_string(),
+            })
+            .collect()
+    }
     pub fn load_next_icons(&mut self, amount: usize) -> bool {
         let mut is_done: bool = false;
         if self.loaded_icons < self.applications.len() {
-            let last: usize = if self.loaded_icons + amount >= self.applications.len() {
-                self.applications.len() - 1
-            } else {
-                self.loaded_icons + amount
-            };
+            let last: usize = (self.loaded_icons + amount).min(self.applications.len());
             for i in self.loaded_icons..last {
+                // Already resolved by the index cache
+                if self.applications[i].icon_path.is_some() {
+                    continue;
+                }
                 self.applications[i].icon_path = match &self.applications[i].icon_name {
                     Some(path) => match lookup_icon(path)
                         .from_theme(&self.icon_theme)
@@ -224,15 +1296,20 @@ impl ApplicationManager {
                     None => None,
                 };
 
-                if let Some(m) = self
-                    .matches
-                    .iter_mut()
-                    .find(|m| m.0.name == self.applications[i].name)
-                {
+                if let Some(m) = self.matches.iter_mut().find(|m| {
+                    m.0.name == self.applications[i].name
+                        && m.0.desktop_id == self.applications[i].desktop_id
+                        && m.0.index == self.applications[i].index
+                }) {
                     m.0.icon_path = self.applications[i].icon_path.clone();
                 };
             }
-            self.loaded_icons += amount;
+            self.loaded_icons = last;
+            if self.loaded_icons == self.applications.len() && !self.stdout_mode {
+                // Writing the cache would stall the UI for a moment
+                let applications: Vec<Application> = self.applications.clone();
+                thread::spawn(move || store_icon_paths(&applications));
+            }
         } else {
             is_done = true;
         }
@@ -240,6 +1317,139 @@ impl ApplicationManager {
     }
 }
 
+/// Loads the applications in a background thread, whenever the application paths change. Keeps
+/// the UI responsive and the window of the daemon quick to show
+fn reload_on_change(
+    changes: Receiver<()>,
+    paths: Vec<String>,
+    desktop_actions: bool,
+    binaries: bool,
+    icon_theme: String,
+) -> Receiver<Vec<Application>> {
+    let (tx, rx) = mpsc::channel::<Vec<Application>>();
+    thread::spawn(move || {
+        while changes.recv().is_ok() {
+            // Changes which arrived in the meantime are covered by this reload
+            changes.try_iter().count();
+            let applications: Vec<Application> =
+                load_applications(&paths, desktop_actions, binaries, &icon_theme);
+            if tx.send(applications).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+/// Collects the applications from the desktop files in `paths` and, if enabled, the executables
+/// in `$PATH`
+fn load_applications(
+    paths: &Vec<String>,
+    desktop_actions: bool,
+    binaries: bool,
+    icon_theme: &str,
+) -> Vec<Application> {
+    let mut applications: Vec<Application> =
+        collect_applications(paths, desktop_actions, icon_theme);
+    if binaries {
+        let binaries: Vec<Application> = collect_binaries(&applications);
+        applications.extend(binaries);
+    }
+    applications.sort();
+    applications
+}
+/// Merges two lists of matches sorted by descending score. On equal scores the matches of `first`
+/// come first, like in a stable sort
+fn merge_by_score(
+    first: Vec<(Application, i64)>,
+    second: Vec<(Application, i64)>,
+) -> Vec<(Application, i64)> {
+    let mut merged: Vec<(Application, i64)> = Vec::with_capacity(first.len() + second.len());
+    let mut second = second.into_iter().peekable();
+    for m in first {
+        while let Some(next) = second.next_if(|next| next.1 > m.1) {
+            merged.push(next);
+        }
+        merged.push(m);
+    }
+    merged.extend(second);
+    merged
+}
+/// Entries piped into aphorme, which are printed instead of launched. `start` is the index of the
+/// first one
+fn stdout_applications(
+    entries: Vec<String>,
+    start: usize,
+    options: &MenuOptions,
+) -> Vec<Application> {
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| parse_row(entry, start + i, options))
+        .collect()
+}
+/// Parses a piped in entry in the extended row syntax of rofi, e.g.
+/// `text\0icon\x1ffolder\x1fmeta\x1fdocuments`. The text is shown and, unless an `info` is
+/// attached, printed once selected. The `meta` terms are searched, but not shown
+fn parse_row(entry: String, index: usize, options: &MenuOptions) -> Application {
+    let (text, properties) = entry.split_once('\0').unwrap_or((&entry, ""));
+    let mut application: Application = Application {
+        name: text.to_owned(),
+        command: text.to_owned(),
+        index,
+        application_type: ApplicationType::Stdout,
+        ..Default::default()
+    };
+    split_fields(&mut application, text, options);
+    if properties.is_empty() {
+        return application;
+    }
+    let properties: Vec<&str> = properties.split('\x1f').collect();
+    for property in properties.chunks(2) {
+        let [key, value] = property else {
+            debug!("Property {:?} of {:?} has no value", property, text);
+            continue;
+        };
+        match *key {
+            "icon" => {
+                // Either the path to an image or the name of an icon of the theme
+                if Path::new(value).is_absolute() && Path::new(value).is_file() {
+                    application.icon_path = Some(PathBuf::from(value));
+                } else {
+                    application.icon_name = Some(value.to_string());
+                }
+            }
+            "meta" => {
+                application.keywords = value.split_whitespace().map(str::to_owned).collect();
+            }
+            "info" => application.command = value.to_string(),
+            "nonselectable" => application.nonselectable = *value == "true",
+            _ => debug!("Ignoring unknown property {:?} of {:?}", key, text),
+        }
+    }
+    application
+}
+/// Splits the text of a piped in entry into fields. The fields of `with_nth` are shown as columns
+/// and searched, the ones of `accept_nth` are printed. Both are joined by the delimiter
+fn split_fields(application: &mut Application, text: &str, options: &MenuOptions) {
+    let fields: Vec<&str> = match &options.delimiter {
+        Some(delimiter) => text.split(delimiter.as_str()).collect(),
+        None => text.split_whitespace().collect(),
+    };
+    let separator: &str = options.delimiter.as_deref().unwrap_or(" ");
+    if let Some(accept_nth) = &options.accept_nth {
+        application.command = accept_nth.select(&fields).join(separator);
+    }
+    // Shown as is, unless the fields are of interest
+    if options.delimiter.is_none() && options.with_nth.is_none() {
+        return;
+    }
+    let columns: Vec<&str> = match &options.with_nth {
+        Some(with_nth) => with_nth.select(&fields),
+        None => fields,
+    };
+    application.name = columns.join(separator);
+    application.columns = columns.into_iter().map(str::to_owned).collect();
+}
 /// A specific application found on the system
 #[derive(Clone, Eq, PartialEq, Default, Serialize, Deserialize, Hash, Debug)]
 pub struct Application {
@@ -251,8 +1461,37 @@ pub struct Application {
     /// Optional icon path, if defined in the desktop file and found in the system
     pub icon_path: Option<PathBuf>,
     pub icon_name: Option<String>,
+    /// Whether the application has to be run inside a terminal emulator
+    terminal: bool,
+    /// Untranslated name of the application, if it differs from the localized name
+    untranslated_name: Option<String>,
+    /// Generic name of the application, e.g. "Web Browser"
+    generic_name: Option<String>,
+    /// Additional search terms from the Keywords entry
+    keywords: Vec<String>,
+    /// Tooltip from the Comment entry
+    comment: Option<String>,
+    /// Menu categories the application belongs to
+    categories: Vec<String>,
+    /// ID of the desktop action, if this is one
+    action: Option<String>,
+    /// Desktop file ID as defined by the Desktop Entry specification, e.g. `kde-konsole.desktop`
+    desktop_id: Option<String>,
+    /// Location of the desktop file the application was read from. Used for the `%k` field code
+    desktop_file: Option<PathBuf>,
     /// The type of application
     application_type: ApplicationType,
+    /// Executable, which has to be installed for the application to be shown
+    try_exec: Option<String>,
+    /// Position of a piped in entry in the input
+    #[serde(skip)]
+    index: usize,
+    /// Whether the piped in entry is only shown, e.g. as a heading
+    #[serde(skip)]
+    nonselectable: bool,
+    /// Fields of the piped in entry, which are shown aligned in a table
+    #[serde(skip)]
+    columns: Vec<String>,
 }
 impl Ord for Application {
     fn cmp(&self, other: &Self) -> Ordering {
@@ -265,96 +1504,968 @@ impl PartialOrd for Application {
     }
 }
 impl Application {
-    /// Executes the program and exits if quit is true
-    pub fn run(&self, quit: bool) {
-        let split_command: Vec<&str> = self.command.split(' ').collect();
-        let mut args: Vec<&str> = Vec::new();
-        for arg in split_command[1..].iter() {
-            if !arg.is_empty() && !arg.starts_with('%') {
-                args.push(arg.to_owned());
-            }
-        }
-        Command::new(split_command[0].trim_matches('\"'))
-            .args(args)
-            .spawn()
-            .unwrap();
+    pub fn is_selectable(&self) -> bool {
+        !self.nonselectable
+    }
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    pub fn desktop_file(&self) -> Option<&Path> {
+        self.desktop_file.as_deref()
+    }
+    /// Stable key under which the usage of the application is remembered, namespaced by the
+    /// application type. Desktop actions share the desktop file ID and thereby the preference of
+    /// their application
+    fn preference_key(&self) -> String {
+        match self.application_type {
+            ApplicationType::DesktopFile => format!(
+                "desktop:{}",
+                self.desktop_id.as_deref().unwrap_or(&self.name)
+            ),
+            ApplicationType::Binary => format!("binary:{}", self.command),
+            ApplicationType::Stdout => format!("dmenu:{}", self.command),
+            ApplicationType::Shell => format!("shell:{}", self.command),
+        }
+    }
+    /// Key identifying the entry in the list, used for pinning and hiding. Unlike the
+    /// `preference_key`, desktop actions are distinguished from their application
+    fn entry_key(&self) -> String {
+        match &self.action {
+            Some(action) => format!("{}#{}", self.preference_key(), action),
+            None => self.preference_key(),
+        }
+    }
+    /// Fuzzy matches the search string against the name, generic name and categories. Keywords
+    /// and the comment only match if each word of the search string starts a word of theirs, as
+    /// fuzzy matches within long texts are mostly noise. Returns the best match scaled by the
+    /// weight of its field
+    fn match_score(
+        &self,
+        matcher: &SkimMatcherV2,
+        search_str: &str,
+        weights: &SearchWeightsCFG,
+    ) -> Option<i64> {
+        let weighted = |field: &str, weight: i64| {
+            matcher
+                .fuzzy_match(field, search_str)
+                .map(|score| score * weight / 100)
+        };
+        let word_prefixes = |field: &str, weight: i64| {
+            word_prefix_score(matcher, field, search_str).map(|score| score * weight / 100)
+        };
+        [
+            weighted(&self.name, weights.name),
+            self.untranslated_name
+                .as_deref()
+                .and_then(|untranslated_name| weighted(untranslated_name, weights.name)),
+            self.generic_name
+                .as_deref()
+                .and_then(|generic_name| weighted(generic_name, weights.generic_name)),
+            self.keywords
+                .iter()
+                .filter_map(|keyword| word_prefixes(keyword, weights.keywords))
+                .max(),
+            self.comment
+                .as_deref()
+                .and_then(|comment| word_prefixes(comment, weights.comment)),
+            self.categories
+                .iter()
+                .filter_map(|category| weighted(category, weights.categories))
+                .max(),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+    }
+    /// Executes the program and exits if quit is true. Programs requiring a terminal are wrapped
+    /// in the `terminal` command
+    pub fn run(&self, terminal: &[String], quit: bool) {
+        let exec_args: Option<Vec<String>> = match self.application_type {
+            ApplicationType::Binary => Some(vec![self.command.clone()]),
+            ApplicationType::Shell => Some(vec![
+                env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()),
+                "-c".to_string(),
+                self.command.clone(),
+            ]),
+            _ => self.exec_args(),
+        };
+        let mut args: Vec<String> = match exec_args {
+            Some(args) => args,
+            None => {
+                error!("Malformed Exec entry {:?} for {}", self.command, self.name);
+                return;
+            }
+        };
+        if args.is_empty() {
+            error!("Empty Exec entry for {}", self.name);
+            return;
+        }
+        if self.terminal {
+            if terminal.is_empty() {
+                warn!(
+                    "No terminal emulator found, launching {} without one",
+                    self.name
+                );
+            } else {
+                args = terminal.iter().cloned().chain(args).collect();
+            }
+        }
+        let Some((program, args)) = args.split_first() else {
+            return;
+        };
+        match Command::new(program).args(args).spawn() {
+            // Reaped in the background, as exited children would otherwise linger as zombies
+            // for as long as the daemon runs
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => error!("Could not launch {}, due to {:?}", self.name, err),
+        }
         if quit {}
     }
+    /// Splits the Exec entry into its arguments as described in the Desktop Entry specification
+    /// and expands the field codes. Returns None if the entry is malformed (e.g. unterminated quotes)
+    fn exec_args(&self) -> Option<Vec<String>> {
+        let command: String = unescape_string(&self.command);
+        let mut args: Vec<String> = Vec::new();
+        let mut current: String = String::new();
+        // Whether the current argument exists, even if it is empty (e.g. `""`)
+        let mut in_argument: bool = false;
+        let mut quoted: bool = false;
+        let mut chars = command.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    in_argument = true;
+                }
+                '\\' => {
+                    current.push(chars.next()?);
+                    in_argument = true;
+                }
+                ' ' | '\t' | '\n' if !quoted => {
+                    if in_argument {
+                        args.push(std::mem::take(&mut current));
+                        in_argument = false;
+                    }
+                }
+                '%' => match chars.next()? {
+                    '%' => {
+                        current.push('%');
+                        in_argument = true;
+                    }
+                    'i' => {
+                        if let Some(icon) = self.icon_name.as_ref().filter(|i| !i.is_empty()) {
+                            if in_argument {
+                                args.push(std::mem::take(&mut current));
+                            }
+                            args.push("--icon".to_string());
+                            current.push_str(icon);
+                            in_argument = true;
+                        }
+                    }
+                    'c' => {
+                        current.push_str(&self.name);
+                        in_argument = true;
+                    }
+                    'k' => {
+                        if let Some(desktop_file) = &self.desktop_file {
+                            current.push_str(&desktop_file.to_string_lossy());
+                            in_argument = true;
+                        }
+                    }
+                    // No files or urls are ever passed, so these expand to nothing. The
+                    // deprecated codes are removed as well
+                    'f' | 'F' | 'u' | 'U' | 'd' | 'D' | 'n' | 'N' | 'v' | 'm' => {}
+                    code => {
+                        warn!("Unknown field code %{} in {:?}", code, self.command);
+                    }
+                },
+                _ => {
+                    current.push(c);
+                    in_argument = true;
+                }
+            }
+        }
+        if quoted {
+            return None;
+        }
+        if in_argument {
+            args.push(current);
+        }
+        Some(args)
+    }
+}
+/// Matches each word of the search string against the start of the words of `field`, regardless
+/// of case. The score is the sum of the best scores of the search words
+fn word_prefix_score(matcher: &SkimMatcherV2, field: &str, search_str: &str) -> Option<i64> {
+    let field: String = field.to_lowercase();
+    let words: Vec<&str> = field
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let search_str: String = search_str.to_lowercase();
+    let mut search_words = search_str.split_whitespace().peekable();
+    search_words.peek()?;
+    search_words
+        .map(|search_word| {
+            words
+                .iter()
+                .filter(|word| word.starts_with(search_word))
+                .filter_map(|word| matcher.fuzzy_match(word, search_word))
+                .max()
+        })
+        .sum()
+}
+/// Builds the default application paths from `XDG_DATA_HOME` and `XDG_DATA_DIRS` as described in
+/// the XDG Base Directory specification
+fn default_application_paths() -> Vec<String> {
+    let non_empty_var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+    application_paths(
+        non_empty_var("XDG_DATA_HOME"),
+        non_empty_var("XDG_DATA_DIRS"),
+    )
+}
+/// The applications directories of the data directories. Falls back to `DATA_HOME` and
+/// `APPLICATION_PATHS` for the ones not given
+fn application_paths(data_home: Option<String>, data_dirs: Option<String>) -> Vec<String> {
+    let mut paths: Vec<String> = vec![format!(
+        "{}/applications",
+        data_home
+            .as_deref()
+            .unwrap_or(DATA_HOME)
+            .trim_end_matches('/')
+    )];
+    match data_dirs {
+        Some(data_dirs) => paths.extend(
+            data_dirs
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| format!("{}/applications", dir.trim_end_matches('/'))),
+        ),
+        None => paths.extend(APPLICATION_PATHS.iter().map(|p| p.to_string())),
+    }
+    let mut seen: HashSet<String> = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    paths
+}
+/// Determines the terminal command from the configured value, `$TERMINAL` or the first of the
+/// common `TERMINAL_EMULATORS` found in `$PATH`. Returns an empty Vec if no terminal was found
+fn resolve_terminal(configured: Option<&str>) -> Vec<String> {
+    if let Some(terminal) = configured {
+        return terminal.split_whitespace().map(|a| a.to_owned()).collect();
+    }
+    if let Some(terminal) = env::var_os("TERMINAL").filter(|t| !t.is_empty()) {
+        let terminal: String = terminal.to_string_lossy().into_owned();
+        let binary_name: &str = terminal.rsplit('/').next().unwrap_or(&terminal);
+        let exec_args: &[&str] = TERMINAL_EMULATORS
+            .iter()
+            .find(|(name, _)| *name == binary_name)
+            .map(|(_, args)| *args)
+            .unwrap_or(&["-e"]);
+        return std::iter::once(terminal.clone())
+            .chain(exec_args.iter().map(|a| a.to_string()))
+            .collect();
+    }
+    for (name, exec_args) in TERMINAL_EMULATORS {
+        if let Some(path) = find_in_path(name) {
+            debug!("Using terminal emulator {:?}", path);
+            return std::iter::once(path.to_string_lossy().into_owned())
+                .chain(exec_args.iter().map(|a| a.to_string()))
+                .collect();
+        }
+    }
+    Vec::new()
+}
+/// Looks up an executable by name in `$PATH`. Names containing a `/` are checked directly
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name)).filter(|p| is_executable(p));
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|p| is_executable(p))
+}
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+/// Resolves the escape sequences allowed in string values of desktop files (`\s`, `\n`, `\t`,
+/// `\r` and `\\`). Unknown sequences are kept as is, so the Exec quoting rules can handle them
+fn unescape_string(value: &str) -> String {
+    let mut unescaped: String = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    unescaped
+}
+/// Checks the NoDisplay, Hidden, OnlyShowIn and NotShowIn keys of a desktop entry, to decide
+/// whether it should be listed in the current desktop environment
+fn should_show(entry: &Entry, path: &Path, current_desktops: &[String]) -> bool {
+    let section = entry.section("Desktop Entry");
+    if section.attr("NoDisplay") == Some("true") {
+        debug!("Skipping {:?}: NoDisplay is set", path);
+        return false;
+    }
+    if section.attr("Hidden") == Some("true") {
+        debug!("Skipping {:?}: Hidden is set", path);
+        return false;
+    }
+    let in_desktops = |list: &str| {
+        list.split(';')
+            .any(|desktop| current_desktops.iter().any(|current| current == desktop))
+    };
+    if let Some(only_show_in) = section.attr("OnlyShowIn") {
+        if !in_desktops(only_show_in) {
+            debug!(
+                "Skipping {:?}: OnlyShowIn={} does not contain any of {:?}",
+                path, only_show_in, current_desktops
+            );
+            return false;
+        }
+    }
+    if let Some(not_show_in) = section.attr("NotShowIn") {
+        if in_desktops(not_show_in) {
+            debug!(
+                "Skipping {:?}: NotShowIn={} contains one of {:?}",
+                path, not_show_in, current_desktops
+            );
+            return false;
+        }
+    }
+    true
+}
+/// Whether the executable of the TryExec entry is installed. Checked on every load, as the
+/// parsed desktop files are cached
+fn try_exec_found(application: &Application) -> bool {
+    let Some(try_exec) = &application.try_exec else {
+        return true;
+    };
+    if find_in_path(try_exec).is_none() {
+        debug!(
+            "Skipping {:?}: TryExec {} not found",
+            application.desktop_file, try_exec
+        );
+        return false;
+    }
+    true
 }
 // fn search_icons(name: &str) {}
-/// Find applications in the APPLICATION_PATHS and return them as a `Vec<Application>`
-pub fn collect_applications(paths: &Vec<String>) -> Vec<Application> {
+/// Find applications in the APPLICATION_PATHS and return them as a `Vec<Application>`.
+/// The paths are expected in order of precedence. If several paths contain a desktop file with
+/// the same desktop file ID, only the first one is considered. Desktop actions are added as
+/// separate applications if `desktop_actions` is set. Parsed desktop files are cached, together with
+/// their icon paths resolved in `icon_theme`
+pub fn collect_applications(
+    paths: &Vec<String>,
+    desktop_actions: bool,
+    icon_theme: &str,
+) -> Vec<Application> {
     debug!("{:#?}", paths);
     let mut applications: Vec<Application> = Vec::new();
+    let current_desktops: Vec<String> = env::var("XDG_CURRENT_DESKTOP")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|desktop| !desktop.is_empty())
+        .map(|desktop| desktop.to_owned())
+        .collect();
+    let locales: Vec<String> = locale_variants();
+    let mut cache: IndexCache = IndexCache::load(
+        format!("{current_desktops:?} {locales:?} {desktop_actions}"),
+        icon_theme,
+    );
+    let mut seen_ids: HashSet<String> = HashSet::new();
 
     for path in paths {
-        match fs::read_dir(path) {
-            Ok(files) => {
-                let path_applications: Vec<Option<Application>> = files
-                    .collect::<Vec<Result<fs::DirEntry, std::io::Error>>>()
-                    .iter()
-                    .map(|file_res| match file_res {
-                        Ok(file) => {
-                            if !file.file_name().to_string_lossy().ends_with(".desktop") {
-                                return None;
-                            }
-                            let entry: Entry = match parse_entry(file.path()) {
-                                Ok(entry) => entry,
-                                Err(err) => {
-                                    error!(
-                                        "Desktop file {} not readable, due to {:?}",
-                                        file.path().to_string_lossy(),
-                                        err
-                                    );
-                                    return None;
-                                }
-                            };
-                            if let Some(nodisplay) =
-                                entry.section("Desktop Entry").attr("NoDisplay")
-                            {
-                                if nodisplay == "true" {
-                                    return None;
-                                }
-                            }
-
-                            let name: Option<&str> = entry.section("Desktop Entry").attr("Name");
-                            let command: Option<&str> = entry.section("Desktop Entry").attr("Exec");
-                            let icon_path: Option<PathBuf> = None;
-                            let icon_name: Option<String> = entry
-                                .section("Desktop Entry")
-                                .attr("Icon")
-                                .map(|icon| icon.to_owned());
-                            match (name, command) {
-                                (Some(name), Some(command)) => Some(Application {
-                                    name: name.into(),
-                                    command: command.into(),
-                                    icon_path,
-                                    icon_name,
-                                    application_type: ApplicationType::DesktopFile,
-                                }),
-                                _ => {
-                                    error!(
-                                        "Incomplete desktop file {}",
-                                        file.path().to_string_lossy()
-                                    );
-                                    None
-                                }
-                            }
-                        }
-                        Err(error) => {
-                            error!("Error encountered while reading file {:?}", error);
-                            None
-                        }
-                    })
-                    .collect();
-                for application in path_applications.iter().flatten() {
-                    applications.push(application.clone());
-                }
+        let mut desktop_files: Vec<(String, PathBuf)> = Vec::new();
+        collect_desktop_files(
+            Path::new(path),
+            "",
+            &mut HashSet::new(),
+            &mut cache,
+            &mut desktop_files,
+        );
+        for (desktop_id, file_path) in desktop_files {
+            // A hidden entry still masks entries with the same ID in paths of lower precedence
+            if !seen_ids.insert(desktop_id.clone()) {
+                debug!(
+                    "Skipping {:?}: {} already defined in a path of higher precedence",
+                    file_path, desktop_id
+                );
+                continue;
+            }
+            let file_applications: Vec<Application> = cache.applications(&file_path, || {
+                parse_applications(
+                    &file_path,
+                    desktop_id.clone(),
+                    &current_desktops,
+                    &locales,
+                    desktop_actions,
+                )
+            });
+            // Shared by the desktop actions of the file
+            if !file_applications.first().is_none_or(try_exec_found) {
+                continue;
             }
+            // The same file may have a different ID, if it is reachable from several paths
+            applications.extend(
+                file_applications
+                    .into_iter()
+                    .map(|application| Application {
+                        desktop_id: Some(desktop_id.clone()),
+                        ..application
+                    }),
+            );
+        }
+    }
+    cache.save();
+    applications
+}
+/// Determines the locale from `LC_ALL`, `LC_MESSAGES` or `LANG` and returns the variants to look
+/// up localized keys with, in the fallback order of the Desktop Entry specification:
+/// `lang_COUNTRY@MODIFIER`, `lang_COUNTRY`, `lang@MODIFIER`, `lang`
+fn locale_variants() -> Vec<String> {
+    let Some(locale) = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+    else {
+        return Vec::new();
+    };
+    let (locale, modifier) = match locale.split_once('@') {
+        Some((locale, modifier)) => (locale, Some(modifier)),
+        None => (locale.as_str(), None),
+    };
+    // The encoding is not part of the key
+    let locale: &str = locale.split('.').next().unwrap_or(locale);
+    if locale == "C" || locale == "POSIX" {
+        return Vec::new();
+    }
+    let (lang, country) = match locale.split_once('_') {
+        Some((lang, country)) => (lang, Some(country)),
+        None => (locale, None),
+    };
+    let mut variants: Vec<String> = Vec::new();
+    if let (Some(country), Some(modifier)) = (country, modifier) {
+        variants.push(format!("{lang}_{country}@{modifier}"));
+    }
+    if let Some(country) = country {
+        variants.push(format!("{lang}_{country}"));
+    }
+    if let Some(modifier) = modifier {
+        variants.push(format!("{lang}@{modifier}"));
+    }
+    variants.push(lang.to_owned());
+    variants
+}
+/// Returns the value of `key` for the first of the `locales` it is translated to, or the
+/// untranslated value
+fn localized_attr<'a, T: AsRef<str>>(
+    section: &'a AttrSelector<'a, T>,
+    key: &str,
+    locales: &[String],
+) -> Option<&'a str> {
+    locales
+        .iter()
+        .find_map(|locale| section.attr_with_param(key, locale))
+        .or_else(|| section.attr(key))
+}
+/// Collects the executables in `$PATH`. Executables shadowed by an earlier `$PATH` entry and those
+/// already launched by one of the desktop `applications` are left out
+fn collect_binaries(applications: &[Application]) -> Vec<Application> {
+    let Some(path_var) = env::var_os("PATH") else {
+        warn!("$PATH is not set, no binaries will be listed");
+        return Vec::new();
+    };
+    // Binaries are compared by name, as desktop files usually don't use the full path. Only
+    // desktop entries running the binary without arguments cover it, so wrappers like `env` or
+    // `flatpak` stay listed
+    let mut seen_names: HashSet<String> = applications
+        .iter()
+        .filter_map(|application| match application.exec_args()?.as_slice() {
+            [program] => Some(program.rsplit('/').next().unwrap_or(program).to_owned()),
+            _ => None,
+        })
+        .collect();
+    let mut binaries: Vec<Application> = Vec::new();
+    for dir in env::split_paths(&path_var) {
+        let files = match fs::read_dir(&dir) {
+            Ok(files) => files,
             Err(error) => {
-                warn!("Could not read {path:?} because of {error:?}")
+                debug!("Could not read {dir:?} because of {error:?}");
+                continue;
+            }
+        };
+        for file in files.flatten() {
+            let name: String = file.file_name().to_string_lossy().into_owned();
+            let path: PathBuf = file.path();
+            if seen_names.contains(&name) || !is_executable(&path) {
+                continue;
             }
+            seen_names.insert(name.clone());
+            binaries.push(Application {
+                name,
+                command: path.to_string_lossy().into_owned(),
+                application_type: ApplicationType::Binary,
+                ..Default::default()
+            });
+        }
+    }
+    binaries
+}
+/// Recursively collects all desktop files below `dir` together with their desktop file ID, which
+/// is the path relative to the application path with `/` replaced by `-`
+fn collect_desktop_files(
+    dir: &Path,
+    id_prefix: &str,
+    visited: &mut HashSet<PathBuf>,
+    cache: &mut IndexCache,
+    desktop_files: &mut Vec<(String, PathBuf)>,
+) {
+    // Guards against symlink loops
+    if let Ok(canonical) = fs::canonicalize(dir) {
+        if !visited.insert(canonical) {
+            return;
+        }
+    }
+    let listing = match cache.list_directory(dir) {
+        Ok(listing) => listing,
+        Err(error) => {
+            warn!("Could not read {dir:?} because of {error:?}");
+            return;
         }
+    };
+    for file_name in listing.desktop_files {
+        desktop_files.push((format!("{id_prefix}{file_name}"), dir.join(file_name)));
     }
+    for subdirectory in listing.subdirectories {
+        collect_desktop_files(
+            &dir.join(&subdirectory),
+            &format!("{id_prefix}{subdirectory}-"),
+            visited,
+            cache,
+            desktop_files,
+        );
+    }
+}
+/// Parses a single desktop file into the application and, if `desktop_actions` is set, one
+/// application per desktop action. Returns an empty Vec if it is unreadable, incomplete or should
+/// not be shown
+fn parse_applications(
+    file_path: &Path,
+    desktop_id: String,
+    current_desktops: &[String],
+    locales: &[String],
+    desktop_actions: bool,
+) -> Vec<Application> {
+    let entry: Entry = match parse_entry(file_path) {
+        Ok(entry) => entry,
+        Err(err) => {
+            error!(
+                "Desktop file {} not readable, due to {:?}",
+                file_path.to_string_lossy(),
+                err
+            );
+            return Vec::new();
+        }
+    };
+    if !should_show(&entry, file_path, current_desktops) {
+        return Vec::new();
+    }
+
+    let section = entry.section("Desktop Entry");
+    let name: Option<&str> = localized_attr(&section, "Name", locales);
+    let command: Option<&str> = entry.section("Desktop Entry").attr("Exec");
+    let terminal: bool = entry
+        .section("Desktop Entry")
+        .attr("Terminal")
+        .is_some_and(|terminal| terminal == "true");
+    let icon_path: Option<PathBuf> = None;
+    let icon_name: Option<String> = entry
+        .section("Desktop Entry")
+        .attr("Icon")
+        .map(|icon| icon.to_owned());
+    let list = |value: Option<&str>| -> Vec<String> {
+        value
+            .unwrap_or_default()
+            .split(';')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.to_owned())
+            .collect()
+    };
+    let (name, command) = match (name, command) {
+        (Some(name), Some(command)) => (name, command),
+        _ => {
+            error!("Incomplete desktop file {}", file_path.to_string_lossy());
+            return Vec::new();
+        }
+    };
+    let application: Application = Application {
+        name: name.into(),
+        command: command.into(),
+        icon_path,
+        icon_name,
+        terminal,
+        untranslated_name: section
+            .attr("Name")
+            .filter(|untranslated_name| *untranslated_name != name)
+            .map(|untranslated_name| untranslated_name.to_owned()),
+        generic_name: localized_attr(&section, "GenericName", locales)
+            .map(|generic_name| generic_name.to_owned()),
+        keywords: list(localized_attr(&section, "Keywords", locales)),
+        comment: localized_attr(&section, "Comment", locales).map(|comment| comment.to_owned()),
+        categories: list(section.attr("Categories")),
+        action: None,
+        desktop_id: Some(desktop_id),
+        desktop_file: Some(file_path.to_path_buf()),
+        application_type: ApplicationType::DesktopFile,
+        try_exec: section.attr("TryExec").map(unescape_string),
+        index: 0,
+        nonselectable: false,
+        columns: Vec::new(),
+    };
+    let mut applications: Vec<Application> = Vec::new();
+    if desktop_actions {
+        let actions: &str = entry
+            .section("Desktop Entry")
+            .attr("Actions")
+            .unwrap_or_default();
+        for action in actions.split(';').filter(|action| !action.is_empty()) {
+            let section = entry.section(format!("Desktop Action {action}"));
+            match (
+                localized_attr(&section, "Name", locales),
+                section.attr("Exec"),
+            ) {
+                (Some(action_name), Some(action_command)) => applications.push(Application {
+                    name: format!("{}: {}", application.name, action_name),
+                    untranslated_name: application.untranslated_name.as_ref().map(
+                        |untranslated_name| {
+                            format!(
+                                "{}: {}",
+                                untranslated_name,
+                                section.attr("Name").unwrap_or(action_name)
+                            )
+                        },
+                    ),
+                    command: action_command.into(),
+                    icon_name: section
+                        .attr("Icon")
+                        .map(|icon| icon.to_owned())
+                        .or_else(|| application.icon_name.clone()),
+                    action: Some(action.to_owned()),
+                    ..application.clone()
+                }),
+                // Actions without Exec are only meant for D-Bus activation
+                _ => debug!(
+                    "Skipping action {} of {:?}: Name or Exec missing",
+                    action, file_path
+                ),
+            }
+        }
+    }
+    applications.insert(0, application);
     applications
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exec_args(command: &str) -> Option<Vec<String>> {
+        Application {
+            name: "Editor".to_owned(),
+            command: command.to_owned(),
+            icon_name: Some("editor".to_owned()),
+            desktop_file: Some(PathBuf::from("/usr/share/applications/editor.desktop")),
+            ..Default::default()
+        }
+        .exec_args()
+    }
+
+    #[test]
+    fn unescape_string_resolves_known_escapes() {
+        assert_eq!(unescape_string(r"a\sb\tc\nd\re"), "a b\tc\nd\re");
+        assert_eq!(unescape_string(r"a\\b"), r"a\b");
+        // Left for the Exec quoting rules
+        assert_eq!(unescape_string(r#"\"a\$"#), r#"\"a\$"#);
+        assert_eq!(unescape_string(r"trailing\"), r"trailing\");
+    }
+
+    #[test]
+    fn exec_args_splits_arguments() {
+        assert_eq!(
+            exec_args("editor  --new\t-x"),
+            Some(vec!["editor".into(), "--new".into(), "-x".into()])
+        );
+        assert_eq!(
+            exec_args(r"editor\sfile"),
+            Some(vec!["editor".into(), "file".into()])
+        );
+    }
+
+    #[test]
+    fn exec_args_handles_quotes_and_escapes() {
+        assert_eq!(
+            exec_args(r#""/opt/my editor/run" --flag"#),
+            Some(vec!["/opt/my editor/run".into(), "--flag".into()])
+        );
+        // `\\` in the desktop file is an escaped backslash, which escapes the quote
+        assert_eq!(
+            exec_args(r#"sh -c "echo \\"hi\\" \\$HOME""#),
+            Some(vec!["sh".into(), "-c".into(), r#"echo "hi" $HOME"#.into()])
+        );
+        assert_eq!(
+            exec_args(r"editor a\\\\b"),
+            Some(vec!["editor".into(), r"a\b".into()])
+        );
+        assert_eq!(
+            exec_args(r#"editor "" x"#),
+            Some(vec!["editor".into(), "".into(), "x".into()])
+        );
+        assert_eq!(
+            exec_args(r#"editor pre"quoted part"post"#),
+            Some(vec!["editor".into(), "prequoted partpost".into()])
+        );
+    }
+
+    #[test]
+    fn exec_args_rejects_invalid_commands() {
+        assert_eq!(exec_args(r#"editor "unterminated"#), None);
+        assert_eq!(exec_args(r"editor \\"), None);
+        assert_eq!(exec_args("editor %"), None);
+    }
+
+    #[test]
+    fn exec_args_expands_field_codes() {
+        assert_eq!(
+            exec_args("editor 100%%"),
+            Some(vec!["editor".into(), "100%".into()])
+        );
+        assert_eq!(
+            exec_args("editor %U %f --new"),
+            Some(vec!["editor".into(), "--new".into()])
+        );
+        assert_eq!(
+            exec_args("editor %i"),
+            Some(vec!["editor".into(), "--icon".into(), "editor".into()])
+        );
+        // The icon is always passed as separate arguments
+        assert_eq!(
+            exec_args("editor x%i"),
+            Some(vec![
+                "editor".into(),
+                "x".into(),
+                "--icon".into(),
+                "editor".into()
+            ])
+        );
+        assert_eq!(
+            exec_args("editor --name=%c %k"),
+            Some(vec![
+                "editor".into(),
+                "--name=Editor".into(),
+                "/usr/share/applications/editor.desktop".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn exec_args_skips_missing_icon() {
+        let application: Application = Application {
+            command: "editor %i --new".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            application.exec_args(),
+            Some(vec!["editor".into(), "--new".into()])
+        );
+    }
+    fn fields(expression: &str) -> Fields {
+        expression.parse::<Fields>().unwrap()
+    }
+
+    #[test]
+    fn fields_parse_indices_and_ranges() {
+        assert!("1,3..".parse::<Fields>().is_ok());
+        assert!("-2..-1".parse::<Fields>().is_ok());
+        assert!("..".parse::<Fields>().is_ok());
+        for invalid in ["0", "", "a", "1,", "1..x", "..0", "1...2"] {
+            assert!(
+                invalid.parse::<Fields>().is_err(),
+                "{invalid:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn fields_select_in_expression_order() {
+        let row: [&str; 4] = ["a", "b", "c", "d"];
+        assert_eq!(fields("1").select(&row), ["a"]);
+        assert_eq!(fields("3,1").select(&row), ["c", "a"]);
+        assert_eq!(fields("-1").select(&row), ["d"]);
+        assert_eq!(fields("2..").select(&row), ["b", "c", "d"]);
+        assert_eq!(fields("..2").select(&row), ["a", "b"]);
+        assert_eq!(fields("-2..").select(&row), ["c", "d"]);
+        assert_eq!(fields("..-2").select(&row), ["a", "b", "c"]);
+        assert_eq!(fields("..").select(&row), row);
+    }
+
+    #[test]
+    fn fields_out_of_range_are_left_out() {
+        let row: [&str; 4] = ["a", "b", "c", "d"];
+        assert!(fields("5").select(&row).is_empty());
+        assert!(fields("-5").select(&row).is_empty());
+        assert!(fields("3..1").select(&row).is_empty());
+        assert_eq!(fields("3..9").select(&row), ["c", "d"]);
+        assert_eq!(fields("-9..2").select(&row), ["a", "b"]);
+        assert!(fields("1").select(&[]).is_empty());
+    }
+
+    #[test]
+    fn parse_row_reads_rofi_properties() {
+        let row: Application = parse_row(
+            "Documents\0icon\x1ffolder\x1fmeta\x1fpapers pdf\x1finfo\x1f/home/docs".to_owned(),
+            3,
+            &MenuOptions::default(),
+        );
+        assert_eq!(row.name, "Documents");
+        assert_eq!(row.command, "/home/docs");
+        assert_eq!(row.icon_name.as_deref(), Some("folder"));
+        assert_eq!(row.keywords, ["papers", "pdf"]);
+        assert_eq!(row.index, 3);
+        assert!(row.is_selectable());
+        let heading: Application = parse_row(
+            "Heading\0nonselectable\x1ftrue\x1ficon\x1f/nonexistent/icon.png".to_owned(),
+            0,
+            &MenuOptions::default(),
+        );
+        assert!(!heading.is_selectable());
+        // Missing files are looked up in the icon theme instead
+        assert_eq!(heading.icon_path, None);
+        assert_eq!(heading.icon_name.as_deref(), Some("/nonexistent/icon.png"));
+    }
+
+    #[test]
+    fn parse_row_ignores_incomplete_properties() {
+        let row: Application = parse_row(
+            "Entry\0info\x1fvalue\x1ficon".to_owned(),
+            0,
+            &MenuOptions::default(),
+        );
+        assert_eq!(row.name, "Entry");
+        assert_eq!(row.command, "value");
+        assert_eq!(row.icon_name, None);
+        let plain: Application = parse_row("Plain entry".to_owned(), 0, &MenuOptions::default());
+        assert_eq!(plain.name, "Plain entry");
+        assert_eq!(plain.command, "Plain entry");
+        assert!(plain.columns.is_empty());
+    }
+
+    #[test]
+    fn parse_row_splits_fields() {
+        let options: MenuOptions = MenuOptions {
+            delimiter: Some("\t".to_owned()),
+            with_nth: Some(fields("2..")),
+            accept_nth: Some(fields("1")),
+            ..Default::default()
+        };
+        let row: Application = parse_row("17\tfirefox\trunning".to_owned(), 0, &options);
+        assert_eq!(row.columns, ["firefox", "running"]);
+        assert_eq!(row.name, "firefox\trunning");
+        assert_eq!(row.command, "17");
+        // An attached info takes precedence over the accepted fields
+        let row: Application = parse_row("18\tvim\0info\x1fx".to_owned(), 0, &options);
+        assert_eq!(row.command, "x");
+        // Runs of whitespace separate the fields without delimiter
+        let options: MenuOptions = MenuOptions {
+            accept_nth: Some(fields("-1")),
+            ..Default::default()
+        };
+        let row: Application = parse_row("a  b   c".to_owned(), 0, &options);
+        assert_eq!(row.name, "a  b   c");
+        assert_eq!(row.command, "c");
+        assert!(row.columns.is_empty());
+    }
+
+    #[test]
+    fn command_history_keeps_commands_of_other_instances() {
+        let home: PathBuf =
+            std::env::temp_dir().join(format!("aphorme-history-{}", std::process::id()));
+        let home_dir: &str = home.to_str().unwrap();
+        let mut first: CommandHistory = CommandHistory::new(home_dir);
+        let mut second: CommandHistory = CommandHistory::new(home_dir);
+        first.add("make");
+        first.save();
+        second.add("ls");
+        second.save();
+        assert_eq!(second.commands, ["ls", "make"]);
+        first.add("ls");
+        first.add("top");
+        first.save();
+
+        assert_eq!(first.commands, ["top", "ls", "make"]);
+        assert_eq!(CommandHistory::new(home_dir).commands, first.commands);
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn application_paths_follow_data_directories() {
+        let fallback: Vec<String> = APPLICATION_PATHS.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            application_paths(None, None),
+            [
+                vec!["$HOME/.local/share/applications".to_owned()],
+                fallback.clone()
+            ]
+            .concat()
+        );
+        assert_eq!(
+            application_paths(Some("/data/".to_owned()), None),
+            [vec!["/data/applications".to_owned()], fallback].concat()
+        );
+        assert_eq!(
+            application_paths(None, Some("/usr/share:/opt/share/::/usr/share".to_owned())),
+            [
+                "$HOME/.local/share/applications",
+                "/usr/share/applications",
+                "/opt/share/applications"
+            ]
+        );
+    }
+
+    #[test]
+    fn comment_matches_word_prefixes_only() {
+        let application: Application = Application {
+            name: "Firefox".to_owned(),
+            comment: Some("Browse the World Wide Web".to_owned()),
+            keywords: vec!["Internet".to_owned(), "WWW".to_owned()],
+            ..Default::default()
+        };
+        let matcher: SkimMatcherV2 = SkimMatcherV2::default();
+        let weights: SearchWeightsCFG = SearchWeightsCFG::default();
+        let score = |search_str: &str| application.match_score(&matcher, search_str, &weights);
+        assert!(score("web brow").is_some());
+        assert!(score("inter").is_some());
+        assert!(score("fox").is_some());
+        // Fuzzy matches of the comment, e.g. B(rowse) t(he) W(orld)
+        assert_eq!(score("btw"), None);
+        assert_eq!(score("rowse"), None);
+        assert_eq!(score(" "), None);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::apps::{save_json, Application};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+/// Location of the index cache, relative to `$XDG_CACHE_HOME`
+const CACHE_FILE: &str = "aphorme/index.json";
+/// Version of the cache format. Caches of other versions are discarded
+const CACHE_VERSION: u32 = 3;
+/// Content of the cache file
+#[derive(Default, Serialize, Deserialize)]
+struct IndexData {
+    version: u32,
+    /// Everything apart from the desktop files, which influences how they are parsed (desktop
+    /// environment, locale, ...). The cache is discarded if it changes
+    context: String,
+    /// Icon theme the icon paths were resolved with
+    icon_theme: String,
+    directories: HashMap<PathBuf, CachedDirectory>,
+    files: HashMap<PathBuf, CachedFile>,
+}
+/// Identifies the content of a file or directory. Symlinks are resolved, as the path alone
+/// doesn't change when a symlink is swapped, e.g. on a switch to another Nix generation. All
+/// files in the Nix store have the same modification time
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+struct Stamp {
+    /// The path with all symlinks resolved
+    target: PathBuf,
+    /// Inode of the target
+    inode: u64,
+    /// Modification time of the target in nanoseconds since the epoch
+    mtime: u64,
+}
+/// Listing of an application directory
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedDirectory {
+    stamp: Stamp,
+    /// Names of the subdirectories, sorted
+    pub subdirectories: Vec<String>,
+    /// Names of the desktop files, sorted
+    pub desktop_files: Vec<String>,
+}
+/// Applications parsed from a desktop file
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedFile {
+    /// None if the file could not be read
+    stamp: Option<Stamp>,
+    applications: Vec<Application>,
+}
+/// Cache of the parsed desktop files in `$XDG_CACHE_HOME/aphorme`. Directories are only read
+/// again if their `Stamp` changed, desktop files are only parsed again if theirs did
+pub struct IndexCache {
+    path: Option<PathBuf>,
+    /// Content of the cache file
+    cached: IndexData,
+    /// Directories and files used in this run, which will replace the cache file
+    current: IndexData,
+    changed: bool,
+}
+impl IndexCache {
+    pub fn load(context: String, icon_theme: &str) -> Self {
+        let path: Option<PathBuf> = dirs::cache_dir().map(|cache_dir| cache_dir.join(CACHE_FILE));
+        let mut cached: IndexData = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str::<IndexData>(&content).ok())
+            .filter(|cached| cached.version == CACHE_VERSION && cached.context == context)
+            .unwrap_or_default();
+        for cached_file in cached.files.values_mut() {
+            for application in &mut cached_file.applications {
+                let outdated: bool = cached.icon_theme != icon_theme
+                    || application
+                        .icon_path
+                        .as_ref()
+                        .is_some_and(|icon_path| !icon_path.exists());
+                if outdated {
+                    application.icon_path = None;
+                }
+            }
+        }
+        IndexCache {
+            path,
+            cached,
+            current: IndexData {
+                version: CACHE_VERSION,
+                context,
+                icon_theme: icon_theme.to_owned(),
+                ..Default::default()
+            },
+            changed: false,
+        }
+    }
+    /// Lists the subdirectories and desktop files of `dir`. The cached listing is used if the
+    /// stamp of `dir` did not change
+    pub fn list_directory(&mut self, dir: &Path) -> std::io::Result<CachedDirectory> {
+        let stamp: Stamp = stamp(dir)?;
+        let listing: CachedDirectory = match self
+            .cached
+            .directories
+            .get(dir)
+            .filter(|cached| cached.stamp == stamp)
+        {
+            Some(cached) => cached.clone(),
+            None => {
+                debug!("Reading {:?}", dir);
+                self.changed = true;
+                read_directory(dir, stamp)?
+            }
+        };
+        self.current
+            .directories
+            .insert(dir.to_path_buf(), listing.clone());
+        Ok(listing)
+    }
+    /// Returns the cached applications of the desktop file or, if it changed since, the ones
+    /// returned by `parse`
+    pub fn applications(
+        &mut self,
+        file: &Path,
+        parse: impl FnOnce() -> Vec<Application>,
+    ) -> Vec<Application> {
+        let stamp: Option<Stamp> = stamp(file).ok();
+        let cached_file: CachedFile = match self
+            .cached
+            .files
+            .get(file)
+            .filter(|cached| stamp.is_some() && cached.stamp == stamp)
+        {
+            Some(cached) => cached.clone(),
+            None => {
+                self.changed = true;
+                CachedFile {
+                    stamp,
+                    applications: parse(),
+                }
+            }
+        };
+        self.current
+            .files
+            .insert(file.to_path_buf(), cached_file.clone());
+        cached_file.applications
+    }
+    /// Writes the directories and files used in this run to the cache file, if anything changed
+    pub fn save(&self) {
+        let unchanged: bool = !self.changed
+            && self.current.directories.len() == self.cached.directories.len()
+            && self.current.files.len() == self.cached.files.len()
+            && self.current.icon_theme == self.cached.icon_theme;
+        if unchanged {
+            return;
+        }
+        if let Some(path) = &self.path {
+            save_json(
+                path,
+                &serde_json::to_string(&self.current).unwrap_or_default(),
+            );
+        }
+    }
+}
+/// Stores the resolved icon paths of the `applications` in the cache, so they don't have to be
+/// looked up again on the next start
+pub fn store_icon_paths(applications: &[Application]) {
+    let Some(path) = dirs::cache_dir().map(|cache_dir| cache_dir.join(CACHE_FILE)) else {
+        return;
+    };
+    let Some(mut index) = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<IndexData>(&content).ok())
+    else {
+        return;
+    };
+    let mut icon_paths: HashMap<(&Path, &str), &PathBuf> = HashMap::new();
+    for application in applications {
+        if let (Some(desktop_file), Some(icon_path)) =
+            (application.desktop_file(), &application.icon_path)
+        {
+            icon_paths.insert((desktop_file, &application.name), icon_path);
+        }
+    }
+    for (file, cached_file) in &mut index.files {
+        for application in &mut cached_file.applications {
+            if let Some(icon_path) = icon_paths.get(&(file.as_path(), application.name.as_str())) {
+                application.icon_path = Some(icon_path.to_path_buf());
+            }
+        }
+    }
+    save_json(&path, &serde_json::to_string(&index).unwrap_or_default());
+}
+fn read_directory(dir: &Path, stamp: Stamp) -> std::io::Result<CachedDirectory> {
+    let mut listing: CachedDirectory = CachedDirectory {
+        stamp,
+        subdirectories: Vec::new(),
+        desktop_files: Vec::new(),
+    };
+    for file_res in fs::read_dir(dir)? {
+        let file = match file_res {
+            Ok(file) => file,
+            Err(error) => {
+                error!("Error encountered while reading file {:?}", error);
+                continue;
+            }
+        };
+        let file_name: String = file.file_name().to_string_lossy().into_owned();
+        if file.path().is_dir() {
+            listing.subdirectories.push(file_name);
+        } else if file_name.ends_with(".desktop") {
+            listing.desktop_files.push(file_name);
+        }
+    }
+    // read_dir makes no guarantees about the order, which would make duplicates within one
+    // path nondeterministic
+    listing.subdirectories.sort();
+    listing.desktop_files.sort();
+    Ok(listing)
+}
+/// Stamp of the target of `path`, following symlinks
+fn stamp(path: &Path) -> std::io::Result<Stamp> {
+    let target: PathBuf = fs::canonicalize(path)?;
+    let metadata: fs::Metadata = fs::metadata(&target)?;
+    let mtime: u64 = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok(Stamp {
+        target,
+        inode: metadata.ino(),
+        mtime,
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::fs::symlink;
+    use std::time::{Duration, SystemTime};
+
+    fn empty_cache() -> IndexCache {
+        IndexCache {
+            path: None,
+            cached: IndexData::default(),
+            current: IndexData::default(),
+            changed: false,
+        }
+    }
+    /// Creates `dir` with a desktop file named `app.desktop`, all with the same modification time
+    /// like in the Nix store
+    fn store_directory(dir: &Path, content: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("app.desktop"), content).unwrap();
+        let mtime: SystemTime = UNIX_EPOCH + Duration::from_secs(1);
+        File::open(dir.join("app.desktop"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        File::open(dir).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn swapped_symlink_invalidates_cache() {
+        let root: PathBuf =
+            std::env::temp_dir().join(format!("aphorme-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        store_directory(&root.join("generation-1"), "first");
+        store_directory(&root.join("generation-2"), "second");
+        let link: PathBuf = root.join("applications");
+        symlink(root.join("generation-1"), &link).unwrap();
+        let parse = |file: &Path| {
+            let mut application: Application = Application::default();
+            application.name = fs::read_to_string(file).unwrap();
+            vec![application]
+        };
+
+        let mut cache: IndexCache = empty_cache();
+        cache.list_directory(&link).unwrap();
+        let file: PathBuf = link.join("app.desktop");
+        assert_eq!(cache.applications(&file, || parse(&file))[0].name, "first");
+
+        // Same path and modification time, but another target
+        fs::remove_file(&link).unwrap();
+        symlink(root.join("generation-2"), &link).unwrap();
+        let mut cache: IndexCache = IndexCache {
+            cached: std::mem::take(&mut cache.current),
+            ..empty_cache()
+        };
+        cache.list_directory(&link).unwrap();
+        assert!(cache.changed, "the listing of the old target was used");
+        assert_eq!(cache.applications(&file, || parse(&file))[0].name, "second");
+
+        // Unchanged targets are still cached
+        let mut cache: IndexCache = IndexCache {
+            cached: std::mem::take(&mut cache.current),
+            ..empty_cache()
+        };
+        cache.list_directory(&link).unwrap();
+        assert_eq!(
+            cache.applications(&file, || panic!("parsed again"))[0].name,
+            "second"
+        );
+        assert!(!cache.changed);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
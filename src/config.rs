@@ -45,19 +45,70 @@ impl Default for GuiCFG {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PrefCFG {
     pub max_weight: i64,
+    /// Days after which a launch only counts half
+    pub half_life_days: Option<f64>,
+    /// Weight of an application, which was recently chosen for the same search string
+    pub query_weight: Option<i64>,
+    /// Remember the selected entries piped in with `--select-from-stdin` as well
+    pub remember_piped_entries: Option<bool>,
+}
+/// Weights in percent, with which matches in the respective desktop file keys are scored. Weights
+/// left out keep their default
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SearchWeightsCFG {
+    pub name: i64,
+    pub generic_name: i64,
+    pub keywords: i64,
+    pub comment: i64,
+    pub categories: i64,
+}
+impl Default for SearchWeightsCFG {
+    fn default() -> Self {
+        SearchWeightsCFG {
+            name: 100,
+            generic_name: 80,
+            keywords: 70,
+            comment: 40,
+            categories: 30,
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AppCFG {
     pub paths: Vec<String>,
     pub use_default_paths: Option<bool>,
     pub preferred_apps: PrefCFG,
+    /// Terminal emulator (including the flag to execute a program) used for `Terminal=true`
+    /// applications, e.g. "alacritty -e". Auto-detected if not set
+    pub terminal: Option<String>,
+    /// List desktop actions (e.g. "Firefox: New Private Window") as separate entries
+    pub desktop_actions: Option<bool>,
+    pub search_weights: Option<SearchWeightsCFG>,
+    /// List the executables found in `$PATH` as well
+    pub binaries: Option<bool>,
+    /// Search strings starting with this prefix are run as shell commands
+    pub shell_prefix: Option<String>,
+    /// Run shell commands typed into the search box in the terminal
+    pub shell_in_terminal: Option<bool>,
 }
 impl Default for AppCFG {
     fn default() -> Self {
         AppCFG {
             paths: Vec::new(),
             use_default_paths: Some(true),
-            preferred_apps: PrefCFG { max_weight: 10 },
+            preferred_apps: PrefCFG {
+                max_weight: 10,
+                half_life_days: Some(14.0),
+                query_weight: Some(30),
+                remember_piped_entries: Some(false),
+            },
+            terminal: None,
+            desktop_actions: Some(true),
+            search_weights: Some(SearchWeightsCFG::default()),
+            binaries: Some(false),
+            shell_prefix: Some(">".to_string()),
+            shell_in_terminal: Some(false),
         }
     }
 }
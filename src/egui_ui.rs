@@ -1,20 +1,42 @@
 #[cfg(feature = "egui-ui")]
 pub mod ui {
-    use std::{fs::File, io::Read};
+    use std::{cell::Cell, fs, path::Path, rc::Rc};
 
-    use crate::apps::ApplicationManager;
+    use crate::apps::{Application, ApplicationManager, MenuOptions, EXIT_CUSTOM_KEY};
     use crate::config::GuiCFG;
+    use crate::ipc::{Listener, Request};
     use eframe::egui::TextBuffer;
     use eframe::{
-        egui::{self, FontId, Image, Key, RichText, ViewportCommand},
+        egui::{self, FontId, Image, Key, Modifiers, RichText, ViewportCommand},
         epaint::{Color32, Vec2},
     };
 
     use log::debug;
+    /// Alternate accept keys, used with Alt. They select the entry with the exit code
+    /// `EXIT_CUSTOM_KEY` and upwards
+    const CUSTOM_KEYS: [Key; 9] = [
+        Key::Num1,
+        Key::Num2,
+        Key::Num3,
+        Key::Num4,
+        Key::Num5,
+        Key::Num6,
+        Key::Num7,
+        Key::Num8,
+        Key::Num9,
+    ];
+    /// Background of the selected row
+    fn selected_color() -> Color32 {
+        Color32::from_rgba_unmultiplied(0, 100, 0, 128)
+    }
+    /// Shows the window until it is closed and returns the application manager, so it can be
+    /// reused for the next window. Requests of other invocations are answered while the window is
+    /// shown, if a `listener` is passed
     pub fn launch_egui_ui(
         gui_cfg: GuiCFG,
         application_manager: ApplicationManager,
-    ) -> Result<(), eframe::Error> {
+        listener: Option<Rc<Listener>>,
+    ) -> Result<ApplicationManager, eframe::Error> {
         let options = eframe::NativeOptions {
             viewport: egui::ViewportBuilder::default()
                 .with_inner_size([gui_cfg.window_size.0 as f32, gui_cfg.window_size.1 as f32])
@@ -25,15 +47,23 @@ pub mod ui {
             ..Default::default()
         };
 
+        let returned: Rc<Cell<Option<ApplicationManager>>> = Rc::new(Cell::new(None));
+        let returned_ui: Rc<Cell<Option<ApplicationManager>>> = returned.clone();
         eframe::run_native(
             "Aphorme",
             options,
             Box::new(move |cc| {
                 egui_extras::install_image_loaders(&cc.egui_ctx);
 
-                Box::new(EguiUI::new(gui_cfg.clone(), application_manager))
+                Box::new(EguiUI::new(
+                    gui_cfg.clone(),
+                    application_manager,
+                    listener,
+                    returned_ui,
+                ))
             }),
-        )
+        )?;
+        Ok(returned.take().unwrap_or_default())
     }
     struct EguiUI {
         /// Selected element in list of applications
@@ -44,15 +74,63 @@ pub mod ui {
         search_str: String,
         /// The GUI configuration
         gui_cfg: GuiCFG,
+        /// Requests of other invocations of aphorme
+        listener: Option<Rc<Listener>>,
+        /// Receives the application manager once the window is closed
+        returned: Rc<Cell<Option<ApplicationManager>>>,
+        /// Whether the entry to select initially is still to be looked for
+        preselect: bool,
+        /// Whether an entry was selected automatically, so the window is about to close
+        auto_selected: bool,
+        /// Height of a row in the list, measured once it is shown
+        row_height: Option<f32>,
+        /// Height the window was resized to, to fit the number of lines
+        fitted_height: f32,
+        /// Widths of the columns of the piped in entries, so they stay the same while only the
+        /// visible rows are shown
+        column_widths: Vec<f32>,
+        /// Number of entries the column widths were measured for
+        measured_entries: usize,
     }
 
     impl EguiUI {
-        pub fn new(gui_cfg: GuiCFG, application_manager: ApplicationManager) -> Self {
+        pub fn new(
+            gui_cfg: GuiCFG,
+            application_manager: ApplicationManager,
+            listener: Option<Rc<Listener>>,
+            returned: Rc<Cell<Option<ApplicationManager>>>,
+        ) -> Self {
             Self {
                 selected: 0,
+                search_str: application_manager.query().to_string(),
                 application_manager,
-                search_str: "".to_string(),
                 gui_cfg,
+                listener,
+                returned,
+                preselect: true,
+                auto_selected: false,
+                row_height: None,
+                fitted_height: 0.0,
+                column_widths: Vec::new(),
+                measured_entries: 0,
+            }
+        }
+        /// Answers the requests of other invocations of aphorme
+        fn handle_requests(&mut self, ctx: &egui::Context) {
+            let Some(listener) = self.listener.clone() else {
+                return;
+            };
+            for incoming in listener.requests.try_iter() {
+                match incoming.request {
+                    Request::Toggle => ctx.send_viewport_cmd(ViewportCommand::Close),
+                    _ => ctx.send_viewport_cmd(ViewportCommand::Focus),
+                }
+                self.application_manager.handle_request(incoming);
+                self.search_str = self.application_manager.query().to_string();
+                self.selected = 0;
+                self.preselect = true;
+                self.column_widths.clear();
+                self.measured_entries = 0;
             }
         }
         /// Custom scrolling function using the arrow keys or the scroll delta of the mouse wheel.
@@ -69,13 +147,159 @@ pub mod ui {
                     || i.key_pressed(Key::ArrowLeft)
                     || i.raw_scroll_delta.y > 1.0
             });
-            if down && self.selected < self.application_manager.matches.len() - 1 {
+            if down || up {
+                self.preselect = false;
+            }
+            if down && self.selected + 1 < self.application_manager.matches.len() {
                 self.selected += 1;
             }
             if up && self.selected > 0 {
                 self.selected -= 1;
             }
         }
+        /// Widens the columns to fit the fields of the entries which arrived since the last call
+        fn measure_columns(&mut self, ctx: &egui::Context) {
+            let applications: &[Application] = self.application_manager.applications();
+            if applications.len() < self.measured_entries {
+                self.column_widths.clear();
+                self.measured_entries = 0;
+            }
+            let font: FontId = self.font();
+            ctx.fonts(|fonts| {
+                for application in &applications[self.measured_entries..] {
+                    for (i, column) in application.columns().iter().enumerate() {
+                        let width: f32 = fonts
+                            .layout_no_wrap(column.clone(), font.clone(), Color32::PLACEHOLDER)
+                            .size()
+                            .x;
+                        match self.column_widths.get_mut(i) {
+                            Some(max_width) => *max_width = max_width.max(width),
+                            None => self.column_widths.push(width),
+                        }
+                    }
+                }
+            });
+            self.measured_entries = applications.len();
+        }
+        /// Shows the fields of a piped in entry aligned in columns
+        fn show_cells(&self, ui: &mut egui::Ui, application: &Application, image: Option<Image>) {
+            let mut image: Option<Image> = image;
+            for (i, column) in application.columns().iter().enumerate() {
+                let mut width: f32 = self.column_widths.get(i).copied().unwrap_or_default();
+                if i == 0 {
+                    // Space for the icon and the mark or pin
+                    width += ui.fonts(|fonts| {
+                        fonts
+                            .layout_no_wrap("📌 ".to_owned(), self.font(), Color32::PLACEHOLDER)
+                            .size()
+                            .x
+                    }) + self.gui_cfg.font_size as f32
+                        + ui.spacing().item_spacing.x;
+                }
+                ui.allocate_ui_with_layout(
+                    Vec2::new(width, 0.0),
+                    egui::Layout::left_to_right(egui::Align::Center),
+                    |ui| {
+                        ui.set_width(width);
+                        if i == 0 {
+                            self.add_icon(ui, image.take());
+                            ui.label(self.row_label(application, column));
+                        } else {
+                            ui.label(self.cell_text(application, column.clone()));
+                        }
+                    },
+                );
+            }
+        }
+        /// Shows an application in a row of the list. Returns the height of the row
+        fn show_row(&self, ui: &mut egui::Ui, i: usize, application: &Application) -> f32 {
+            let mut background_color: Color32 = Color32::from_rgba_unmultiplied(0, 0, 0, 0);
+            if i == self.selected {
+                background_color = selected_color();
+            }
+            let image: Option<Image> = application.icon_path.as_deref().and_then(load_icon);
+            let response = egui::Frame::none()
+                .fill(background_color)
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        if application.columns().is_empty() {
+                            self.add_icon(ui, image);
+                            ui.label(self.row_label(application, &application.name));
+                        } else {
+                            self.show_cells(ui, application, image);
+                        }
+                    })
+                })
+                .response;
+            response.rect.height() + ui.spacing().item_spacing.y
+        }
+        /// Text of the first column, prefixed if the entry is marked, pinned or hidden
+        fn row_label(&self, application: &Application, text: &str) -> RichText {
+            let label: String = if self.application_manager.is_marked(application) {
+                format!("✔ {}", text)
+            } else if self.application_manager.is_pinned(application) {
+                format!("📌 {}", text)
+            } else if self.application_manager.is_hidden(application) {
+                format!("🚫 {}", text)
+            } else {
+                text.to_owned()
+            };
+            self.cell_text(application, label)
+        }
+        fn font(&self) -> FontId {
+            FontId::proportional(self.gui_cfg.font_size as f32)
+        }
+        fn cell_text(&self, application: &Application, text: String) -> RichText {
+            let text: RichText = RichText::new(text).font(self.font());
+            match application.is_selectable() {
+                true => text,
+                false => text.weak(),
+            }
+        }
+        fn add_icon(&self, ui: &mut egui::Ui, image: Option<Image>) {
+            if let Some(actual_image) = image {
+                ui.add(
+                    actual_image
+                        .max_size(Vec2::new(
+                            self.gui_cfg.font_size as f32,
+                            self.gui_cfg.font_size as f32,
+                        ))
+                        .show_loading_spinner(true),
+                );
+            }
+        }
+    }
+
+    /// Loads the icon, if it is an image egui can show
+    fn load_icon(icon_path: &Path) -> Option<Image<'static>> {
+        let ext_raw = icon_path.extension()?;
+        match ext_raw.to_string_lossy().as_str() {
+            "png" | "jpg" | "jpeg" | "svg" => {
+                let file_uri = icon_path.to_string_lossy().into_owned();
+                let bytes: Vec<u8> = match fs::read(icon_path) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        debug!("Could not read icon {:?}, due to {:?}", icon_path, err);
+                        return None;
+                    }
+                };
+                Some(egui::Image::from_bytes(
+                    format!("bytes://{}", file_uri),
+                    bytes,
+                ))
+            }
+            _ => {
+                debug!("Unknown file extension {:?}", ext_raw);
+                None
+            }
+        }
+    }
+
+    impl Drop for EguiUI {
+        fn drop(&mut self) {
+            self.returned
+                .set(Some(std::mem::take(&mut self.application_manager)));
+        }
     }
 
     impl eframe::App for EguiUI {
@@ -83,121 +307,155 @@ pub mod ui {
             egui::Rgba::TRANSPARENT.to_array() // Make sure we don't paint anything behind the rounded corners
         }
         fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+            self.handle_requests(ctx);
+            self.application_manager.receive_entries();
+            if self.application_manager.reload_applications() {
+                self.selected = self
+                    .selected
+                    .min(self.application_manager.matches.len().saturating_sub(1));
+            }
+            if self.preselect {
+                if let Some(preselection) = self.application_manager.preselection() {
+                    self.selected = preselection;
+                    self.preselect = false;
+                }
+            }
+            if !self.auto_selected && self.application_manager.auto_select() {
+                self.auto_selected = true;
+                self.application_manager.execute_first_match(0, 0);
+                ctx.send_viewport_cmd(ViewportCommand::Close)
+            }
             self.scroll(ctx);
             if self.gui_cfg.retain_focus {
                 ctx.send_viewport_cmd(ViewportCommand::Focus)
             }
+            // Checked before Enter, which would match with modifiers as well
+            let toggle_mark: bool = self.application_manager.stdout_mode()
+                && ctx.input_mut(|i| {
+                    i.consume_key(Modifiers::SHIFT, Key::Enter)
+                        || i.consume_key(Modifiers::CTRL, Key::Space)
+                });
+            if toggle_mark {
+                self.application_manager.toggle_mark(self.selected);
+                if self.selected + 1 < self.application_manager.matches.len() {
+                    self.selected += 1;
+                }
+            }
+            // Left to Enter otherwise, so Ctrl+Enter still launches
+            if self.application_manager.accepts_custom()
+                && ctx.input_mut(|i| i.consume_key(Modifiers::CTRL, Key::Enter))
+                && self.application_manager.accept_custom(0)
+            {
+                ctx.send_viewport_cmd(ViewportCommand::Close)
+            }
+            if let Some(code) = ctx.input_mut(|i| {
+                CUSTOM_KEYS
+                    .iter()
+                    .position(|key| i.consume_key(Modifiers::ALT, *key))
+            }) {
+                if self
+                    .application_manager
+                    .execute_first_match(self.selected, EXIT_CUSTOM_KEY + code as u8)
+                {
+                    ctx.send_viewport_cmd(ViewportCommand::Close)
+                }
+            }
             let execute: bool = ctx.input(|i| i.key_pressed(Key::Enter));
             if ctx.input(|i| i.key_pressed(Key::Escape)) {
                 ctx.send_viewport_cmd(ViewportCommand::Close)
             }
-            if execute {
-                self.application_manager.execute_first_match(self.selected);
+            if execute
+                && self
+                    .application_manager
+                    .execute_first_match(self.selected, 0)
+            {
                 ctx.send_viewport_cmd(ViewportCommand::Close)
             }
+            // Consumed here, as the text field would otherwise handle them (e.g. Ctrl+H deletes)
+            if ctx.input_mut(|i| i.consume_key(Modifiers::CTRL, Key::P)) {
+                self.application_manager.toggle_pinned(self.selected);
+                self.selected = 0;
+            }
+            // Checked before Ctrl+H, which would match with Shift as well
+            if ctx.input_mut(|i| i.consume_key(Modifiers::CTRL | Modifiers::SHIFT, Key::H)) {
+                self.application_manager.toggle_show_hidden();
+                self.selected = 0;
+            }
+            if ctx.input_mut(|i| i.consume_key(Modifiers::CTRL, Key::H)) {
+                self.application_manager.toggle_hidden(self.selected);
+                self.selected = self
+                    .selected
+                    .min(self.application_manager.matches.len().saturating_sub(1));
+            }
             if self.gui_cfg.icon {
                 self.application_manager.load_next_icons(5);
             }
+            self.measure_columns(ctx);
+            let options: MenuOptions = self.application_manager.options().clone();
             egui::CentralPanel::default().show(ctx, |ui| {
-                let response = ui.add(egui::TextEdit::singleline(&mut self.search_str));
+                let panel_top: f32 = ui.cursor().top();
+                let response = ui
+                    .horizontal(|ui| {
+                        if let Some(prompt) = &options.prompt {
+                            ui.label(prompt);
+                        }
+                        let mut text_edit = egui::TextEdit::singleline(&mut self.search_str);
+                        if let Some(placeholder) = &options.placeholder {
+                            text_edit = text_edit.hint_text(placeholder);
+                        }
+                        ui.add(text_edit)
+                    })
+                    .inner;
                 response.request_focus();
                 if response.changed() {
                     self.application_manager.find_application(&self.search_str);
                     self.selected = 0;
+                    self.preselect = false;
                 }
                 ui.separator();
+                if let (Some(lines), Some(row_height)) = (options.lines, self.row_height) {
+                    // The bottom margin of the panel equals the top one
+                    let height: f32 = ui.cursor().top() + lines as f32 * row_height + panel_top;
+                    if (height - self.fitted_height).abs() > 0.5 {
+                        self.fitted_height = height;
+                        ctx.send_viewport_cmd(ViewportCommand::InnerSize(Vec2::new(
+                            self.gui_cfg.window_size.0 as f32,
+                            height,
+                        )));
+                    }
+                }
 
-                egui::ScrollArea::vertical()
+                // Estimated until a row is shown
+                let row_height: f32 = self
+                    .row_height
+                    .unwrap_or(self.gui_cfg.font_size as f32 + ui.spacing().item_spacing.y);
+                let mut scroll_area = egui::ScrollArea::vertical()
                     .max_width(f32::INFINITY)
-                    .auto_shrink([false; 2])
-                    .show(ui, |ui| {
-                        for (i, (application, _)) in (self.application_manager.matches.clone())
-                            .iter()
-                            .enumerate()
-                        {
-                            let label_text: RichText = RichText::new(application.name.clone())
-                                .font(FontId::proportional(self.gui_cfg.font_size as f32));
-                            let mut background_color: Color32 =
-                                Color32::from_rgba_unmultiplied(0, 0, 0, 0);
-                            if i == self.selected {
-                                background_color = Color32::from_rgba_unmultiplied(0, 100, 0, 128);
-                            }
-                            let icon_path = &application.icon_path.clone();
-                            let image: Option<Image> = match icon_path {
-                                Some(icon_path) => {
-                                    if let Some(ext_raw) = icon_path.extension() {
-                                        match ext_raw.to_string_lossy().as_str() {
-                                            "png" | "jpg" | "jpeg" | "svg" => {
-                                                let file_uri =
-                                                    icon_path.to_string_lossy().into_owned();
-                                                // println!("{}", file_uri);
-                                                let mut file = File::open(&file_uri.clone())
-                                                    .expect("Failed to open image file");
-                                                let mut bytes = Vec::new();
-                                                file.read_to_end(&mut bytes)
-                                                    .expect("Failed to read image file");
-
-                                                Some(egui::Image::from_bytes(
-                                                    format!("bytes://{}", file_uri),
-                                                    bytes,
-                                                ))
-                                            }
-                                            _ => {
-                                                debug!("Unknown file extension {:?}", ext_raw);
-                                                None
-                                            }
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                }
-                                None => None,
-                            };
-
-                            // let icon: TextureId = match icon_texture_handle {
-                            //     Some(handle) => handle,
-                            //     None => match self.placeholder_icon {
-                            //         Some(icon_handle) => icon_handle,
-                            //         None => {
-                            //             let icon_handle = ctx
-                            //                 .load_texture(
-                            //                     "placeholder",
-                            //                     egui::ColorImage::new(
-                            //                         [8, 8],
-                            //                         Color32::from_rgba_unmultiplied(0, 0, 0, 0),
-                            //                     ),
-                            //                     Default::default(),
-                            //                 )
-                            //                 .id();
-                            //             self.placeholder_icon = Some(icon_handle);
-                            //             icon_handle
-                            //         }
-                            //     },
-                            // };
-                            let response = egui::Frame::none()
-                                .fill(background_color)
-                                .show(ui, |ui| {
-                                    ui.horizontal(|ui| {
-                                        if let Some(actual_image) = image {
-                                            ui.add(
-                                                actual_image
-                                                    .max_size(Vec2::new(
-                                                        self.gui_cfg.font_size as f32,
-                                                        self.gui_cfg.font_size as f32,
-                                                    ))
-                                                    .show_loading_spinner(true),
-                                            );
-                                        }
-                                        ui.label(label_text);
-                                    })
-                                })
-                                .response;
-                            if i == self.selected {
-                                response.scroll_to_me(Some(egui::Align::Min));
+                    .auto_shrink([false; 2]);
+                if self.row_height.is_some() {
+                    // Keeps the selected row on top
+                    scroll_area =
+                        scroll_area.vertical_scroll_offset(self.selected as f32 * row_height);
+                }
+                let matches: &[(Application, i64)] = &self.application_manager.matches;
+                let measured: Option<f32> = scroll_area
+                    .show_rows(
+                        ui,
+                        row_height - ui.spacing().item_spacing.y,
+                        matches.len(),
+                        |ui, rows| {
+                            let mut measured: Option<f32> = None;
+                            for i in rows {
+                                let height: f32 = self.show_row(ui, i, &matches[i].0);
+                                measured.get_or_insert(height);
                             }
-                            // std::process::exit(0);
-                        }
-                    });
+                            measured
+                        },
+                    )
+                    .inner;
+                if self.row_height.is_none() {
+                    self.row_height = measured;
+                }
             });
             ctx.request_repaint();
         }
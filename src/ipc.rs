@@ -0,0 +1,246 @@
+use crate::apps::{MenuOptions, Selection};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{DirBuilder, File, TryLockError};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::{env, fs, thread};
+/// Name of the socket in `$XDG_RUNTIME_DIR`
+const SOCKET_NAME: &str = "aphorme.sock";
+/// Request sent by a new invocation of aphorme to the running instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    /// Show the window, or focus it if it is already shown. Replaces the search string, if a
+    /// query is given
+    Show {
+        query: Option<String>,
+        options: MenuOptions,
+    },
+    /// Show the window, or close it if it is already shown
+    Toggle,
+    /// Show the entries instead of the applications and answer with the selected one, like
+    /// `--select-from-stdin` does. The entries follow the request, one per line, until the
+    /// sending side of the connection is shut down
+    Select {
+        query: Option<String>,
+        options: MenuOptions,
+    },
+}
+/// Answer of the running instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Response {
+    Ok,
+    /// Outcome of a `Request::Select`. Cancelled if the window was closed without selection or
+    /// the entries were replaced by another request
+    Selection(Selection),
+    Error(String),
+}
+/// Request received from another invocation, which still has to be answered
+#[derive(Debug)]
+pub struct Incoming {
+    pub request: Request,
+    /// Entries of a `Request::Select`, as they arrive
+    pub entries: Option<Receiver<String>>,
+    reply: Sender<Response>,
+}
+impl Incoming {
+    pub fn reply(self, response: Response) {
+        if self.reply.send(response).is_err() {
+            debug!("Could not answer request, as the connection is gone");
+        }
+    }
+}
+/// Name of the lock file, which is held by the instance listening on the socket
+const LOCK_NAME: &str = "aphorme.lock";
+/// Directory of the socket. `$XDG_RUNTIME_DIR` or else a directory in the temporary directory,
+/// which only the user can access. Other users could otherwise take the socket over
+fn socket_dir() -> io::Result<PathBuf> {
+    if let Some(runtime_dir) = dirs::runtime_dir() {
+        return Ok(runtime_dir);
+    }
+    // SAFETY: geteuid has no preconditions and can't fail
+    let uid: u32 = unsafe { libc::geteuid() };
+    let dir: PathBuf = env::temp_dir().join(format!("aphorme-{uid}"));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    // Might have been created by another user beforehand
+    let metadata: fs::Metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{dir:?} is not private to the user"),
+        ));
+    }
+    Ok(dir)
+}
+/// Connects to the running instance. Fails if there is none
+pub fn connect() -> io::Result<UnixStream> {
+    UnixStream::connect(socket_dir()?.join(SOCKET_NAME))
+}
+/// Sends the request and, while waiting for the answer, the `entries` as they arrive
+pub fn send(
+    mut stream: UnixStream,
+    request: &Request,
+    entries: Option<Receiver<String>>,
+) -> io::Result<Response> {
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    match entries {
+        Some(entries) => {
+            let mut writer: BufWriter<UnixStream> = BufWriter::new(stream.try_clone()?);
+            thread::spawn(move || {
+                while let Ok(entry) = entries.recv() {
+                    // Written in batches of what is available
+                    for entry in std::iter::once(entry).chain(entries.try_iter()) {
+                        if writeln!(writer, "{entry}").is_err() {
+                            return;
+                        }
+                    }
+                    if writer.flush().is_err() {
+                        return;
+                    }
+                }
+                let _ = writer.get_ref().shutdown(Shutdown::Write);
+            });
+        }
+        None => stream.shutdown(Shutdown::Write)?,
+    }
+    let mut line: String = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+/// Listens for requests of other invocations. The socket is removed again when the returned
+/// guard is dropped
+pub struct Listener {
+    pub requests: Receiver<Incoming>,
+    path: PathBuf,
+    /// Held as long as the socket is bound, so no other instance removes it
+    _lock: File,
+}
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+/// Binds the socket and forwards all incoming requests. Fails if another instance is listening
+/// already. Each connection is kept open until its request is answered
+pub fn listen() -> io::Result<Listener> {
+    let dir: PathBuf = socket_dir()?;
+    let path: PathBuf = dir.join(SOCKET_NAME);
+    let lock: File = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_NAME))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another instance is already running",
+            ))
+        }
+        Err(TryLockError::Error(err)) => return Err(err),
+    }
+    // Left behind by an instance, which did not exit cleanly
+    if path.exists() {
+        debug!("Removing stale socket {:?}", path);
+        fs::remove_file(&path)?;
+    }
+    let listener: UnixListener = UnixListener::bind(&path)?;
+    let (tx, rx) = mpsc::channel::<Incoming>();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream: UnixStream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Could not accept connection, due to {:?}", err);
+                    continue;
+                }
+            };
+            let mut reader: BufReader<UnixStream> = match stream.try_clone() {
+                Ok(reader) => BufReader::new(reader),
+                Err(err) => {
+                    error!("Could not read request, due to {:?}", err);
+                    continue;
+                }
+            };
+            let mut line: String = String::new();
+            if let Err(err) = reader.read_line(&mut line) {
+                debug!("Could not read request, due to {:?}", err);
+                continue;
+            }
+            // Connections without a request only check whether an instance is running
+            if line.is_empty() {
+                continue;
+            }
+            let request: Request = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("Invalid request {:?}: {}", line, err);
+                    answer(stream, Response::Error(err.to_string()));
+                    continue;
+                }
+            };
+            debug!("Received {:?}", request);
+            let entries: Option<Receiver<String>> = match request {
+                Request::Select { .. } => Some(forward_entries(reader)),
+                _ => None,
+            };
+            let (reply, response) = mpsc::channel::<Response>();
+            if tx
+                .send(Incoming {
+                    request,
+                    entries,
+                    reply,
+                })
+                .is_err()
+            {
+                // The receiving end is gone, so the instance is shutting down
+                break;
+            }
+            // Selections can take a while, so they are awaited without blocking other requests
+            thread::spawn(move || {
+                let response: Response = response
+                    .recv()
+                    .unwrap_or_else(|_| Response::Error("request was dropped".to_string()));
+                answer(stream, response);
+            });
+        }
+    });
+    Ok(Listener {
+        requests: rx,
+        path,
+        _lock: lock,
+    })
+}
+fn answer(mut stream: UnixStream, response: Response) {
+    if let Err(err) = writeln!(
+        stream,
+        "{}",
+        serde_json::to_string(&response).unwrap_or_default()
+    ) {
+        debug!("Could not answer request, due to {:?}", err);
+    }
+}
+/// Forwards the entries following a `Request::Select`
+fn forward_entries(reader: BufReader<UnixStream>) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        for entry in reader.lines() {
+            let Ok(entry) = entry else {
+                return;
+            };
+            if tx.send(entry).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
@@ -1,22 +1,25 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 #![deny(clippy::print_stdout)] // #![feature(map_try_insert)]
 mod apps;
+mod cache;
 mod config;
 mod egui_ui;
 mod iced_ui;
-use crate::apps::ApplicationManager;
-use crate::config::{load_config, Config};
+mod ipc;
+mod watcher;
+use crate::apps::{ApplicationManager, Fields, MenuOptions, Selection, EXIT_ERROR};
+use crate::config::{load_config, Config, GuiCFG};
 #[cfg(feature = "egui-ui")]
 use crate::egui_ui::ui::launch_egui_ui;
 #[cfg(feature = "iced-ui")]
 use crate::iced_ui::iced_ui::launch_iced_ui;
+use crate::ipc::{Listener, Request, Response};
+use clap::builder::NonEmptyStringValueParser;
 use clap::Parser;
 use config::UIFramework;
-use log::{debug, error};
-use single_instance::SingleInstance;
-use std::error::Error;
-use std::sync::mpsc::RecvTimeoutError;
-use std::time::Duration;
+use log::{debug, error, warn};
+use std::process::ExitCode;
+use std::rc::Rc;
 use std::{io, io::prelude::*, sync::mpsc, sync::mpsc::Receiver, thread};
 
 #[derive(Parser, Debug)]
@@ -24,72 +27,257 @@ use std::{io, io::prelude::*, sync::mpsc, sync::mpsc::Receiver, thread};
 struct Args {
     #[arg(long)]
     select_from_stdin: bool,
+    /// Keep running in the background and show the window whenever aphorme is started again
+    #[arg(long)]
+    daemon: bool,
+    /// Close the window of the running instance if it is shown, show it otherwise
+    #[arg(long)]
+    toggle: bool,
+    /// Initial search string. Replaces the search string of the running instance
+    #[arg(long)]
+    query: Option<String>,
+    /// Prompt shown in front of the search field
+    #[arg(short, long)]
+    prompt: Option<String>,
+    /// Match regardless of case
+    #[arg(short = 'i', long)]
+    case_insensitive: bool,
+    /// Number of rows shown. The window is resized to fit them
+    #[arg(short, long)]
+    lines: Option<usize>,
+    /// Text shown in the empty search field
+    #[arg(long)]
+    placeholder: Option<String>,
+    /// Select the entry with this text initially
+    #[arg(long)]
+    select: Option<String>,
+    /// Select the remaining entry right away, once only one matches
+    #[arg(long)]
+    auto_select: bool,
+    /// What is printed for the selection. `s` is replaced by its text, `i` by its index, `d` by
+    /// its index starting at 1 and `f` by the search string
+    #[arg(long)]
+    format: Option<String>,
+    /// Print the search string, if nothing matches or with Ctrl+Enter
+    #[arg(long)]
+    allow_custom: bool,
+    /// Separate the selected entries by null characters instead of newlines
+    #[arg(short = '0', long)]
+    null: bool,
+    /// Separates the fields of the piped in entries. Runs of whitespace, if not given
+    #[arg(short, long, value_parser = NonEmptyStringValueParser::new())]
+    delimiter: Option<String>,
+    /// Fields shown and searched, e.g. `2..` or `1,3`. Negative indices count from the last field
+    #[arg(long)]
+    with_nth: Option<Fields>,
+    /// Fields printed for the selected entry, e.g. `1` or `-1`
+    #[arg(long)]
+    accept_nth: Option<Fields>,
 }
-fn main() -> Result<(), Box<dyn Error>> {
+impl Args {
+    fn menu_options(&self) -> MenuOptions {
+        MenuOptions {
+            prompt: self.prompt.clone(),
+            case_insensitive: self.case_insensitive,
+            lines: self.lines,
+            placeholder: self.placeholder.clone(),
+            select: self.select.clone(),
+            auto_select: self.auto_select,
+            format: self.format.clone(),
+            allow_custom: self.allow_custom,
+            delimiter: self.delimiter.clone(),
+            with_nth: self.with_nth.clone(),
+            accept_nth: self.accept_nth.clone(),
+        }
+    }
+}
+fn main() -> ExitCode {
     let args: Args = Args::parse();
-    let mut custom_inputs: Vec<String> = Vec::new();
+    let mut custom_inputs: Option<Receiver<String>> = None;
     if args.select_from_stdin {
-        fetch_custom_commands(&mut custom_inputs)?;
+        custom_inputs = Some(spawn_stdin_channel());
     }
 
-    let instance = SingleInstance::new("Aphorme").unwrap();
     let _ = env_logger::builder()
         .target(env_logger::Target::Stderr)
         .try_init();
-    if instance.is_single() {
-        let cfg: Config = load_config(None);
-        let application_manager: ApplicationManager = ApplicationManager::new(
-            cfg.app_cfg.unwrap_or_default(),
+    // Handed to the running instance, if there is one
+    if !args.daemon {
+        match ipc::connect() {
+            Ok(stream) => {
+                let request: Request = if args.select_from_stdin {
+                    Request::Select {
+                        query: args.query.clone(),
+                        options: args.menu_options(),
+                    }
+                } else if args.toggle {
+                    Request::Toggle
+                } else {
+                    Request::Show {
+                        query: args.query.clone(),
+                        options: args.menu_options(),
+                    }
+                };
+                return match ipc::send(stream, &request, custom_inputs) {
+                    Ok(Response::Ok) => ExitCode::SUCCESS,
+                    Ok(Response::Selection(selection)) => print_selection(selection, args.null),
+                    Ok(Response::Error(err)) => {
+                        error!("The running instance could not handle the request: {err}");
+                        ExitCode::from(EXIT_ERROR)
+                    }
+                    Err(err) => {
+                        error!("Could not reach the running instance, due to {err}");
+                        ExitCode::from(EXIT_ERROR)
+                    }
+                };
+            }
+            Err(err) => debug!("No running instance found ({err:?}), starting a new one"),
+        }
+    }
+    let listener: Option<Rc<Listener>> = match ipc::listen() {
+        Ok(listener) => Some(Rc::new(listener)),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            error!("Could not listen for other invocations, due to {err}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+        Err(err) => {
+            warn!("Could not listen for other invocations, due to {err}");
+            None
+        }
+    };
+    let cfg: Config = load_config(None);
+    if !args.daemon {
+        let mut application_manager: ApplicationManager = ApplicationManager::new(
+            cfg.app_cfg.clone().unwrap_or_default(),
             cfg.gui_cfg.icon,
-            instance,
             custom_inputs,
         );
-        let gui_framework: UIFramework = cfg.gui_cfg.ui_framework.unwrap_or_default();
-        // let gui_framework: GuiFramework = GuiFramework::EGUI; //cfg.ui_framework.unwrap_or_default();
-        match gui_framework {
-            UIFramework::Egui => {
-                #[cfg(feature = "egui-ui")]
-                match launch_egui_ui(cfg.gui_cfg, application_manager) {
-                    Ok(()) => {}
-                    Err(error) => error!("{error:?}"),
-                };
-                #[cfg(not(feature = "egui-ui"))]
-                panic!("Trying to use egui without \"ui-egui\"-feature activated");
-            }
-
-            UIFramework::Iced => {
-                #[cfg(feature = "iced-ui")]
-                launch_iced_ui(cfg.gui_cfg, application_manager);
-            }
+        application_manager.set_options(args.menu_options());
+        if let Some(query) = &args.query {
+            application_manager.find_application(query);
         }
-    } else {
-        error!("another instance is already running");
+        let mut selection: Option<Selection> = None;
+        if let Some(mut application_manager) =
+            launch_ui(cfg.gui_cfg.clone(), application_manager, listener)
+        {
+            selection = application_manager.take_selection();
+            // Answers invocations still waiting for a selection
+            application_manager.reset();
+        }
+        if args.select_from_stdin {
+            return print_selection(selection.unwrap_or_else(Selection::cancelled), args.null);
+        }
+        return ExitCode::SUCCESS;
     }
-    Ok(())
+    let Some(listener) = listener else {
+        error!("Can not run as daemon without listening for other invocations");
+        return ExitCode::from(EXIT_ERROR);
+    };
+    let new_application_manager = || {
+        ApplicationManager::new(
+            cfg.app_cfg.clone().unwrap_or_default(),
+            cfg.gui_cfg.icon,
+            None,
+        )
+    };
+    let mut application_manager: ApplicationManager = new_application_manager();
+    if cfg.gui_cfg.icon {
+        // Loaded up front, so the window is complete as soon as it is shown
+        while !application_manager.load_next_icons(100) {}
+    }
+    // Every request shows the window, as it is hidden while waiting
+    while let Ok(incoming) = listener.requests.recv() {
+        debug!("Showing window due to {:?}", incoming.request);
+        application_manager.handle_request(incoming);
+        application_manager = match launch_ui(
+            cfg.gui_cfg.clone(),
+            application_manager,
+            Some(listener.clone()),
+        ) {
+            Some(mut application_manager) => {
+                application_manager.reset();
+                application_manager
+            }
+            None => new_application_manager(),
+        };
+    }
+    ExitCode::SUCCESS
+}
+/// Prints the outputs of the selection, each terminated by a newline or null character, and
+/// returns its exit code
+#[allow(clippy::print_stdout)]
+fn print_selection(selection: Selection, null: bool) -> ExitCode {
+    let terminator: char = if null { '\0' } else { '\n' };
+    for output in selection.outputs {
+        print!("{output}{terminator}");
+    }
+    let _ = io::stdout().flush();
+    ExitCode::from(selection.exit_code)
 }
-/// Gets custom inputs piped into the program
-/// If present this will replace the default applications and output the selection to stdout
-fn fetch_custom_commands(custom_inputs: &mut Vec<String>) -> Result<(), RecvTimeoutError> {
-    let stdin_channel = spawn_stdin_channel();
+/// Shows the window of the configured UI framework until it is closed. Returns the application
+/// manager for reuse, unless the UI failed
+fn launch_ui(
+    gui_cfg: GuiCFG,
+    application_manager: ApplicationManager,
+    listener: Option<Rc<Listener>>,
+) -> Option<ApplicationManager> {
+    let gui_framework: UIFramework = gui_cfg.ui_framework.unwrap_or_default();
+    // let gui_framework: GuiFramework = GuiFramework::EGUI; //cfg.ui_framework.unwrap_or_default();
+    match gui_framework {
+        UIFramework::Egui => {
+            #[cfg(feature = "egui-ui")]
+            match launch_egui_ui(gui_cfg, application_manager, listener) {
+                Ok(application_manager) => Some(application_manager),
+                Err(error) => {
+                    error!("{error:?}");
+                    None
+                }
+            }
+            #[cfg(not(feature = "egui-ui"))]
+            panic!("Trying to use egui without \"ui-egui\"-feature activated");
+        }
 
-    let key = stdin_channel.recv_timeout(Duration::from_secs(1))?;
-    for line in key.split('\n') {
-        let command: String = line.to_string().replace('\n', "");
-        if !command.is_empty() {
-            custom_inputs.push(command);
+        UIFramework::Iced => {
+            let options: &MenuOptions = application_manager.options();
+            if options.prompt.is_some()
+                || options.lines.is_some()
+                || options.placeholder.is_some()
+                || options.select.is_some()
+                || options.auto_select
+            {
+                warn!("--prompt, --lines, --placeholder, --select and --auto-select are only supported by the egui UI");
+            }
+            #[cfg(feature = "iced-ui")]
+            launch_iced_ui(gui_cfg, application_manager);
+            None
         }
     }
-    Ok(())
 }
-/// Thread which tries to read from the stin
+/// Thread which reads the custom inputs piped into the program line by line, until stdin is
+/// closed. If present they replace the default applications and the selection is output to stdout
 fn spawn_stdin_channel() -> Receiver<String> {
     let (tx, rx) = mpsc::channel::<String>();
-    thread::spawn(move || loop {
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
         let mut buffer: Vec<u8> = Vec::new();
-        io::stdin().lock().read_to_end(&mut buffer).unwrap();
-        if let Err(error) = tx.send(String::from_utf8_lossy(buffer.as_slice()).to_string()) {
-            debug!("Unable to send {:#?}, due to {:?}", buffer, error);
-            break;
+        loop {
+            buffer.clear();
+            match stdin.read_until(b'\n', &mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(error) => {
+                    debug!("Unable to read from stdin, due to {:?}", error);
+                    break;
+                }
+            }
+            let command: String = String::from_utf8_lossy(&buffer).replace('\n', "");
+            if command.is_empty() {
+                continue;
+            }
+            if let Err(error) = tx.send(command) {
+                debug!("Unable to send {:?}, due to {:?}", error.0, error);
+                break;
+            }
         }
     });
     rx
@@ -0,0 +1,141 @@
+use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::{debug, error, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+/// Time without further changes, before the applications are reloaded. Installing a package
+/// usually touches many files at once
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Which events of a watched directory concern the application paths
+enum Relevance {
+    /// An application path or one of its subdirectories, so every change counts
+    All,
+    /// A parent of an application path. Only changes to these entries count, e.g. a symlink like
+    /// Nix's `current-home` being replaced by the next generation
+    Entries(HashSet<OsString>),
+}
+/// Watches the application paths with inotify. A message is sent every time the applications
+/// have to be reloaded
+pub fn watch_applications(paths: Vec<PathBuf>) -> Option<Receiver<()>> {
+    let mut inotify: Inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(err) => {
+            warn!("Not watching application paths, due to {:?}", err);
+            return None;
+        }
+    };
+    // Added before returning, so no change after loading the applications is missed
+    let mut relevance: HashMap<WatchDescriptor, Relevance> =
+        add_watches(&mut inotify.watches(), &paths);
+    let (tx, rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        let mut buffer: [u8; 4096] = [0; 4096];
+        loop {
+            let changed: bool = match inotify.read_events_blocking(&mut buffer) {
+                Ok(mut events) => events.any(|event| is_relevant(&relevance, &event)),
+                Err(err) => {
+                    error!("Stopped watching application paths, due to {:?}", err);
+                    return;
+                }
+            };
+            if !changed {
+                continue;
+            }
+            // Waits until nothing changed for a while. Reading fails if there are no events
+            loop {
+                thread::sleep(DEBOUNCE);
+                if inotify.read_events(&mut buffer).is_err() {
+                    break;
+                }
+            }
+            debug!("Application paths changed");
+            // Directories may have been created, removed or swapped
+            relevance = add_watches(&mut inotify.watches(), &paths);
+            if tx.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    Some(rx)
+}
+fn is_relevant(relevance: &HashMap<WatchDescriptor, Relevance>, event: &Event<&OsStr>) -> bool {
+    if event.mask.contains(EventMask::Q_OVERFLOW) {
+        return true;
+    }
+    match relevance.get(&event.wd) {
+        Some(Relevance::All) => true,
+        Some(Relevance::Entries(entries)) => event.name.is_some_and(|name| entries.contains(name)),
+        None => false,
+    }
+}
+/// Watches the application paths, their subdirectories and their parents. Watches of previous
+/// calls are kept, but their events are ignored unless they are part of the returned map
+fn add_watches(watches: &mut Watches, paths: &[PathBuf]) -> HashMap<WatchDescriptor, Relevance> {
+    let mut relevance: HashMap<WatchDescriptor, Relevance> = HashMap::new();
+    let parent_mask: WatchMask = WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVE
+        | WatchMask::ONLYDIR
+        | WatchMask::MASK_ADD;
+    for path in paths {
+        for (parent, entry) in path.ancestors().skip(1).zip(path.ancestors()) {
+            let (Ok(wd), Some(entry)) = (watches.add(parent, parent_mask), entry.file_name())
+            else {
+                continue;
+            };
+            match relevance
+                .entry(wd)
+                .or_insert_with(|| Relevance::Entries(HashSet::new()))
+            {
+                Relevance::All => {}
+                Relevance::Entries(entries) => {
+                    entries.insert(entry.to_os_string());
+                }
+            }
+        }
+        watch_directory(watches, path, &mut HashSet::new(), &mut relevance);
+    }
+    relevance
+}
+fn watch_directory(
+    watches: &mut Watches,
+    dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    relevance: &mut HashMap<WatchDescriptor, Relevance>,
+) {
+    // Guards against symlink loops
+    if let Ok(canonical) = fs::canonicalize(dir) {
+        if !visited.insert(canonical) {
+            return;
+        }
+    }
+    let mask: WatchMask = WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::ATTRIB
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE_SELF
+        | WatchMask::ONLYDIR
+        | WatchMask::MASK_ADD;
+    match watches.add(dir, mask) {
+        Ok(wd) => {
+            relevance.insert(wd, Relevance::All);
+        }
+        // Most of the default paths don't exist. Their parents are watched in case they are
+        // created
+        Err(_) => return,
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.path().is_dir() {
+            watch_directory(watches, &entry.path(), visited, relevance);
+        }
+    }
+}